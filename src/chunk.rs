@@ -1,59 +1,475 @@
-#[path = "value.rs"]
-pub mod value;
+use crate::value;
 
-#[derive(Default, Debug, Clone, Copy)]
+// one byte opcodes, their operands follow them in the code
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum OpCode {
-    OpConstant(value::Value),
+    // one byte constant index
+    OpConstant,
+    // three byte constant index, for chunks with more than 256 constants
+    OpConstantLong,
+    OpNil,
+    OpTrue,
+    OpFalse,
+    OpEqual,
+    OpGreater,
+    OpLess,
     OpNegate,
     OpAdd,
     OpSubtract,
     OpMultiply,
     OpDivide,
-    #[default]
+    OpNot,
+    OpPrint,
+    OpPop,
+    // the operand is the constant holding the name of the variable
+    OpDefineGlobal,
+    OpGetGlobal,
+    OpSetGlobal,
+    // the operand is the stack slot of the local
+    OpGetLocal,
+    OpSetLocal,
+    // two byte distance to jump, forwards for jumps and backwards for loops
+    OpJump,
+    OpJumpIfFalse,
+    OpLoop,
+    // the operand is the number of arguments, the callee sits right below them
+    OpCall,
+    // the operand is the constant holding the function to wrap. it is followed by
+    // an (is_local, index) pair of bytes for every variable the closure captures
+    OpClosure,
+    // the operand is the index into the current closure's upvalues
+    OpGetUpvalue,
+    OpSetUpvalue,
+    OpCloseUpvalue,
+    // the operand is the constant holding the name of the class, property or method
+    OpClass,
+    OpGetProperty,
+    OpSetProperty,
+    OpMethod,
+    // name constant and argument count, looks up a method and calls it
+    OpInvoke,
+    // copies the superclass's methods down into the subclass
+    OpInherit,
+    OpGetSuper,
+    OpSuperInvoke,
     OpReturn,
+    // prefix that widens the constant operand of the next instruction to three
+    // bytes, for chunks with more than 256 constants
+    OpWide,
+}
+
+impl OpCode {
+    pub fn from_byte(byte: u8) -> Option<OpCode> {
+        let op_code = match byte {
+            0 => OpCode::OpConstant,
+            1 => OpCode::OpConstantLong,
+            2 => OpCode::OpNil,
+            3 => OpCode::OpTrue,
+            4 => OpCode::OpFalse,
+            5 => OpCode::OpEqual,
+            6 => OpCode::OpGreater,
+            7 => OpCode::OpLess,
+            8 => OpCode::OpNegate,
+            9 => OpCode::OpAdd,
+            10 => OpCode::OpSubtract,
+            11 => OpCode::OpMultiply,
+            12 => OpCode::OpDivide,
+            13 => OpCode::OpNot,
+            14 => OpCode::OpPrint,
+            15 => OpCode::OpPop,
+            16 => OpCode::OpDefineGlobal,
+            17 => OpCode::OpGetGlobal,
+            18 => OpCode::OpSetGlobal,
+            19 => OpCode::OpGetLocal,
+            20 => OpCode::OpSetLocal,
+            21 => OpCode::OpJump,
+            22 => OpCode::OpJumpIfFalse,
+            23 => OpCode::OpLoop,
+            24 => OpCode::OpCall,
+            25 => OpCode::OpClosure,
+            26 => OpCode::OpGetUpvalue,
+            27 => OpCode::OpSetUpvalue,
+            28 => OpCode::OpCloseUpvalue,
+            29 => OpCode::OpClass,
+            30 => OpCode::OpGetProperty,
+            31 => OpCode::OpSetProperty,
+            32 => OpCode::OpMethod,
+            33 => OpCode::OpInvoke,
+            34 => OpCode::OpInherit,
+            35 => OpCode::OpGetSuper,
+            36 => OpCode::OpSuperInvoke,
+            37 => OpCode::OpReturn,
+            38 => OpCode::OpWide,
+            _ => return None,
+        };
+        Some(op_code)
+    }
+}
+
+impl From<OpCode> for u8 {
+    fn from(op_code: OpCode) -> u8 {
+        op_code as u8
+    }
+}
+
+// an instruction read out of the code together with its operands.
+// the VM and the disassembler both go through Chunk::decode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    OpConstant(usize),
+    OpConstantLong(usize),
+    OpNil,
+    OpTrue,
+    OpFalse,
+    OpEqual,
+    OpGreater,
+    OpLess,
+    OpNegate,
+    OpAdd,
+    OpSubtract,
+    OpMultiply,
+    OpDivide,
+    OpNot,
+    OpPrint,
+    OpPop,
+    OpDefineGlobal(usize),
+    OpGetGlobal(usize),
+    OpSetGlobal(usize),
+    OpGetLocal(usize),
+    OpSetLocal(usize),
+    OpJump(usize),
+    OpJumpIfFalse(usize),
+    OpLoop(usize),
+    OpCall(usize),
+    // the capture pairs are read separately with Chunk::decode_capture
+    OpClosure(usize),
+    OpGetUpvalue(usize),
+    OpSetUpvalue(usize),
+    OpCloseUpvalue,
+    OpClass(usize),
+    OpGetProperty(usize),
+    OpSetProperty(usize),
+    OpMethod(usize),
+    OpInvoke(usize, usize),
+    OpInherit,
+    OpGetSuper(usize),
+    OpSuperInvoke(usize, usize),
+    OpReturn,
+}
+
+// where in the source an instruction came from, columns start at 1
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Span {
+    pub line: i32,
+    pub column: usize,
+    pub length: usize,
+}
+
+// every this many runs the table starts over from a known position, so a
+// lookup only has to decode the runs after the nearest checkpoint
+const CHECKPOINT_INTERVAL: usize = 32;
+
+// the first run after a checkpoint, it is encoded as if it came first
+#[derive(Debug, Clone, Copy)]
+struct Checkpoint {
+    position: u32,
+    start: u32,
+}
+
+// run-length encoded source positions. A run starts wherever the span changes
+// and is stored as four varints: the bytes since the previous run started, the
+// change in line (zigzag encoded), then the column and length of the span.
+// most runs fit in four bytes
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    bytes: Vec<u8>,
+    checkpoints: Vec<Checkpoint>,
+    runs: usize,
+    // the start and span of the run being extended
+    last: Option<(u32, Span)>,
+}
+
+fn write_varint(bytes: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        bytes.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+// None if the varint is cut off or doesn't fit in a u32
+fn read_varint(bytes: &[u8], position: &mut usize) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *bytes.get(*position)?;
+        *position += 1;
+        let bits = (byte & 0x7f) as u32;
+        if shift == 28 && bits > 0x0f {
+            return None;
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+impl LineTable {
+    pub fn init_line_table() -> Self {
+        Self::default()
+    }
+
+    fn push(&mut self, offset: usize, span: Span) {
+        let start = offset as u32;
+        if self.last.is_some_and(|(_, last)| last == span) {
+            return;
+        }
+        let previous = match self.last {
+            Some((start, last)) if !self.runs.is_multiple_of(CHECKPOINT_INTERVAL) => {
+                (start, last.line)
+            }
+            _ => {
+                self.checkpoints.push(Checkpoint {
+                    position: self.bytes.len() as u32,
+                    start,
+                });
+                (0, 0)
+            }
+        };
+        write_varint(&mut self.bytes, start - previous.0);
+        let line = span.line.wrapping_sub(previous.1);
+        write_varint(&mut self.bytes, ((line << 1) ^ (line >> 31)) as u32);
+        write_varint(&mut self.bytes, span.column as u32);
+        write_varint(&mut self.bytes, span.length as u32);
+        self.runs += 1;
+        self.last = Some((start, span));
+    }
+
+    // decodes the run at position, given the start and line of the one before it
+    fn read_run(&self, position: &mut usize, previous: (u32, i32)) -> Option<(u32, Span)> {
+        let start = previous
+            .0
+            .checked_add(read_varint(&self.bytes, position)?)?;
+        let line = read_varint(&self.bytes, position)?;
+        let line = ((line >> 1) as i32 ^ -((line & 1) as i32)).wrapping_add(previous.1);
+        let column = read_varint(&self.bytes, position)? as usize;
+        let length = read_varint(&self.bytes, position)? as usize;
+        Some((
+            start,
+            Span {
+                line,
+                column,
+                length,
+            },
+        ))
+    }
+
+    // the span of the last run starting at or before offset
+    fn find(&self, offset: usize) -> Option<Span> {
+        let index = self
+            .checkpoints
+            .partition_point(|x| x.start as usize <= offset);
+        let checkpoint = self.checkpoints.get(index.checked_sub(1)?)?;
+        let mut position = checkpoint.position as usize;
+        let mut found = self.read_run(&mut position, (0, 0))?;
+        for _ in 1..CHECKPOINT_INTERVAL {
+            match self.read_run(&mut position, (found.0, found.1.line)) {
+                Some(run) if run.0 as usize <= offset => found = run,
+                _ => break,
+            }
+        }
+        Some(found.1)
+    }
+
+    // the encoded runs, which is also how a compiled file stores them
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    // the table back from as_bytes, None if the runs don't decode
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut table = Self {
+            bytes: bytes.to_vec(),
+            ..Self::default()
+        };
+        let mut position = 0;
+        while position < table.bytes.len() {
+            let run_position = position;
+            let run = match table.last {
+                Some((start, last)) if !table.runs.is_multiple_of(CHECKPOINT_INTERVAL) => {
+                    table.read_run(&mut position, (start, last.line))?
+                }
+                _ => {
+                    let run = table.read_run(&mut position, (0, 0))?;
+                    table.checkpoints.push(Checkpoint {
+                        position: run_position as u32,
+                        start: run.0,
+                    });
+                    run
+                }
+            };
+            table.runs += 1;
+            table.last = Some(run);
+        }
+        Some(table)
+    }
+
+    // roughly how much memory the table holds on to
+    pub fn byte_size(&self) -> usize {
+        self.bytes.capacity() + self.checkpoints.capacity() * std::mem::size_of::<Checkpoint>()
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Chunk {
-    pub code: Vec<OpCode>,
+    pub code: Vec<u8>,
     pub count: usize,
     pub constants: value::ValueArray,
-    pub lines: Vec<i32>,
+    pub lines: LineTable,
 }
 
 impl Chunk {
     pub fn init_chunk() -> Self {
-        let code_store: Vec<OpCode> = Vec::with_capacity(8);
+        let code_store: Vec<u8> = Vec::with_capacity(8);
         Self {
             code: code_store,
             count: 0,
             constants: value::ValueArray::init_value_array(),
-            lines: Vec::<i32>::with_capacity(8),
+            lines: LineTable::init_line_table(),
         }
     }
 
-    pub fn add_constant(&mut self, value: value::Value) -> OpCode {
+    // returns the index of the constant, the caller picks the instruction to load it with
+    pub fn add_constant(&mut self, value: value::Value) -> usize {
         self.constants.write_value_array(value);
-        OpCode::OpConstant(value)
-    }
-
-    pub fn write_chunk(&mut self, inst: OpCode, line: i32) {
-        self.code.reserve_exact(2 * self.code.capacity());
-        self.lines.reserve_exact(2 * self.lines.capacity());
-
-        // if self.lines.is_empty() {
-        self.lines.push(line);
-        // }
-        // else {
-        // self.code[self.count] = inst;
-        // }
-
-        // if self.code.is_empty() {
-        self.code.push(inst);
-        // }
-        // else {
-        // self.lines[self.count] = line;
-        // }
+        self.constants.count - 1
+    }
+
+    pub fn write_chunk(&mut self, byte: u8, span: Span) {
+        // grow by doubling, but only once the storage is actually full
+        if self.code.len() == self.code.capacity() {
+            self.code.reserve_exact(self.code.capacity().max(8));
+        }
+        self.lines.push(self.code.len(), span);
+        self.code.push(byte);
         self.count += 1;
     }
+
+    pub fn get_line(&self, offset: usize) -> i32 {
+        self.get_span(offset).line
+    }
+
+    pub fn get_span(&self, offset: usize) -> Span {
+        self.lines.find(offset).unwrap_or_default()
+    }
+
+    fn read_byte(&self, offset: usize) -> Option<usize> {
+        self.code.get(offset).map(|x| *x as usize)
+    }
+
+    // jump offsets are stored big endian
+    fn read_short(&self, offset: usize) -> Option<usize> {
+        Some(self.read_byte(offset)? << 8 | self.read_byte(offset + 1)?)
+    }
+
+    fn read_long(&self, offset: usize) -> Option<usize> {
+        Some(self.read_byte(offset)? << 16 | self.read_short(offset + 1)?)
+    }
+
+    // decodes the instruction starting at offset, along with the offset of the
+    // next one. None if the byte isn't an opcode or its operands are cut off
+    pub fn decode(&self, offset: usize) -> Option<(Instruction, usize)> {
+        let op_code = OpCode::from_byte(self.code.get(offset).copied()?)?;
+        let operand = offset + 1;
+        let byte = || self.read_byte(operand);
+        let short = || self.read_short(operand);
+        let decoded = match op_code {
+            OpCode::OpConstant => (Instruction::OpConstant(byte()?), operand + 1),
+            OpCode::OpConstantLong => (
+                Instruction::OpConstantLong(self.read_long(operand)?),
+                operand + 3,
+            ),
+            OpCode::OpNil => (Instruction::OpNil, operand),
+            OpCode::OpTrue => (Instruction::OpTrue, operand),
+            OpCode::OpFalse => (Instruction::OpFalse, operand),
+            OpCode::OpEqual => (Instruction::OpEqual, operand),
+            OpCode::OpGreater => (Instruction::OpGreater, operand),
+            OpCode::OpLess => (Instruction::OpLess, operand),
+            OpCode::OpNegate => (Instruction::OpNegate, operand),
+            OpCode::OpAdd => (Instruction::OpAdd, operand),
+            OpCode::OpSubtract => (Instruction::OpSubtract, operand),
+            OpCode::OpMultiply => (Instruction::OpMultiply, operand),
+            OpCode::OpDivide => (Instruction::OpDivide, operand),
+            OpCode::OpNot => (Instruction::OpNot, operand),
+            OpCode::OpPrint => (Instruction::OpPrint, operand),
+            OpCode::OpPop => (Instruction::OpPop, operand),
+            OpCode::OpDefineGlobal => (Instruction::OpDefineGlobal(byte()?), operand + 1),
+            OpCode::OpGetGlobal => (Instruction::OpGetGlobal(byte()?), operand + 1),
+            OpCode::OpSetGlobal => (Instruction::OpSetGlobal(byte()?), operand + 1),
+            OpCode::OpGetLocal => (Instruction::OpGetLocal(byte()?), operand + 1),
+            OpCode::OpSetLocal => (Instruction::OpSetLocal(byte()?), operand + 1),
+            OpCode::OpJump => (Instruction::OpJump(short()?), operand + 2),
+            OpCode::OpJumpIfFalse => (Instruction::OpJumpIfFalse(short()?), operand + 2),
+            OpCode::OpLoop => (Instruction::OpLoop(short()?), operand + 2),
+            OpCode::OpCall => (Instruction::OpCall(byte()?), operand + 1),
+            OpCode::OpClosure => (Instruction::OpClosure(byte()?), operand + 1),
+            OpCode::OpGetUpvalue => (Instruction::OpGetUpvalue(byte()?), operand + 1),
+            OpCode::OpSetUpvalue => (Instruction::OpSetUpvalue(byte()?), operand + 1),
+            OpCode::OpCloseUpvalue => (Instruction::OpCloseUpvalue, operand),
+            OpCode::OpClass => (Instruction::OpClass(byte()?), operand + 1),
+            OpCode::OpGetProperty => (Instruction::OpGetProperty(byte()?), operand + 1),
+            OpCode::OpSetProperty => (Instruction::OpSetProperty(byte()?), operand + 1),
+            OpCode::OpMethod => (Instruction::OpMethod(byte()?), operand + 1),
+            OpCode::OpInvoke => (
+                Instruction::OpInvoke(byte()?, self.read_byte(operand + 1)?),
+                operand + 2,
+            ),
+            OpCode::OpInherit => (Instruction::OpInherit, operand),
+            OpCode::OpGetSuper => (Instruction::OpGetSuper(byte()?), operand + 1),
+            OpCode::OpSuperInvoke => (
+                Instruction::OpSuperInvoke(byte()?, self.read_byte(operand + 1)?),
+                operand + 2,
+            ),
+            OpCode::OpReturn => (Instruction::OpReturn, operand),
+            OpCode::OpWide => return self.decode_wide(operand),
+        };
+        Some(decoded)
+    }
+
+    // the instruction after an OpWide, which decodes the same as without the
+    // prefix apart from its three byte constant. only instructions with a
+    // constant operand can be widened
+    fn decode_wide(&self, offset: usize) -> Option<(Instruction, usize)> {
+        let op_code = OpCode::from_byte(self.code.get(offset).copied()?)?;
+        let operand = offset + 1;
+        let constant = self.read_long(operand)?;
+        let decoded = match op_code {
+            OpCode::OpDefineGlobal => Instruction::OpDefineGlobal(constant),
+            OpCode::OpGetGlobal => Instruction::OpGetGlobal(constant),
+            OpCode::OpSetGlobal => Instruction::OpSetGlobal(constant),
+            OpCode::OpClosure => Instruction::OpClosure(constant),
+            OpCode::OpClass => Instruction::OpClass(constant),
+            OpCode::OpGetProperty => Instruction::OpGetProperty(constant),
+            OpCode::OpSetProperty => Instruction::OpSetProperty(constant),
+            OpCode::OpMethod => Instruction::OpMethod(constant),
+            OpCode::OpGetSuper => Instruction::OpGetSuper(constant),
+            OpCode::OpInvoke => {
+                let arg_count = self.read_byte(operand + 3)?;
+                return Some((Instruction::OpInvoke(constant, arg_count), operand + 4));
+            }
+            OpCode::OpSuperInvoke => {
+                let arg_count = self.read_byte(operand + 3)?;
+                return Some((Instruction::OpSuperInvoke(constant, arg_count), operand + 4));
+            }
+            _ => return None,
+        };
+        Some((decoded, operand + 3))
+    }
+
+    // one of the (is_local, index) pairs following an OpClosure
+    pub fn decode_capture(&self, offset: usize) -> Option<(bool, usize)> {
+        Some((self.read_byte(offset)? == 1, self.read_byte(offset + 1)?))
+    }
 }
@@ -1,11 +1,12 @@
+use std::collections::HashMap;
 use std::str::FromStr;
 
-#[path = "scanner.rs"]
-pub mod scanner;
-
-use crate::chunk::Chunk;
 use crate::chunk::OpCode;
-use crate::debug::*;
+use crate::chunk::{Chunk, Span};
+use crate::diagnostic::Diagnostic;
+use crate::object::{Heap, Obj, ObjFunction, ObjRef};
+use crate::scanner;
+use crate::value::Value;
 
 const PREC_NONE: u8 = 1;
 const PREC_ASSIGNMENT: u8 = 2; // =
@@ -17,167 +18,462 @@ const PREC_TERM: u8 = 7; // + -
 const PREC_FACTOR: u8 = 8; // * /
 const PREC_UNARY: u8 = 9; // ! -
 const PREC_CALL: u8 = 10; // . ()
-const PREC_PRIMARY: u8 = 11;
+
+// a local can't be more than a byte away from the start of the stack window
+const LOCALS_MAX: usize = 256;
+const ARGS_MAX: usize = 255;
+const UPVALUES_MAX: usize = 256;
+
+// locals live on the VM stack, the compiler only tracks which slot holds which name
+#[derive(Debug, Clone)]
+struct Local {
+    // copied out of the source, the compiler also declares names nobody wrote like `this`
+    name: String,
+    // -1 while the initializer is being compiled
+    depth: i32,
+    // captured locals are moved to the heap instead of popped when their scope ends
+    is_captured: bool,
+}
+
+// where a closure finds a captured variable when it is created: a local slot
+// of the enclosing function, or one of the enclosing function's own upvalues
+#[derive(Debug, Clone, Copy)]
+struct Upvalue {
+    index: usize,
+    is_local: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    Script,
+}
+
+// one for every class declaration being compiled, innermost first
+#[derive(Debug)]
+struct ClassCompiler {
+    enclosing: Option<Box<ClassCompiler>>,
+    has_superclass: bool,
+}
+
+// per function state, a new one is pushed for every function body being compiled
+#[derive(Debug)]
+struct Compiler {
+    enclosing: Option<Box<Compiler>>,
+    function: ObjFunction,
+    kind: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: i32,
+    // name constants already in the chunk, every name is stored once
+    names: HashMap<ObjRef, usize>,
+}
+
+impl Compiler {
+    fn init_compiler(kind: FunctionType, name: Option<ObjRef>) -> Self {
+        let mut locals = Vec::<Local>::with_capacity(LOCALS_MAX);
+        // slot zero holds the function being called, it can't be named by the user.
+        // in methods it holds the receiver instead, which is named by `this`
+        let slot_zero = match kind {
+            FunctionType::Method | FunctionType::Initializer => "this",
+            _ => "",
+        };
+        locals.push(Local {
+            name: slot_zero.to_owned(),
+            depth: 0,
+            is_captured: false,
+        });
+        Self {
+            enclosing: None,
+            function: ObjFunction::init_function(name),
+            kind,
+            locals,
+            upvalues: Vec::<Upvalue>::new(),
+            scope_depth: 0,
+            names: HashMap::new(),
+        }
+    }
+
+    // walk the locals backwards so inner declarations shadow outer ones
+    fn resolve_local(&self, name: &str) -> Result<Option<usize>, &'static str> {
+        for (slot, local) in self.locals.iter().enumerate().rev() {
+            if local.name == name {
+                if local.depth == -1 {
+                    return Err("Can't read local variable in its own initializer.");
+                }
+                return Ok(Some(slot));
+            }
+        }
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, index: usize, is_local: bool) -> Result<usize, &'static str> {
+        // a closure captures each variable only once, however often it is used
+        if let Some(existing) = self
+            .upvalues
+            .iter()
+            .position(|x| x.index == index && x.is_local == is_local)
+        {
+            return Ok(existing);
+        }
+        if self.upvalues.len() == UPVALUES_MAX {
+            return Err("Too many closure variables in function.");
+        }
+        self.upvalues.push(Upvalue { index, is_local });
+        self.function.upvalue_count = self.upvalues.len();
+        Ok(self.upvalues.len() - 1)
+    }
+
+    // look for the variable in the enclosing functions, threading it through
+    // every function in between so each one can hand it to the next
+    fn resolve_upvalue(&mut self, name: &str) -> Result<Option<usize>, &'static str> {
+        let enclosing = match self.enclosing.as_mut() {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        if let Some(local) = enclosing.resolve_local(name)? {
+            enclosing.locals[local].is_captured = true;
+            return self.add_upvalue(local, true).map(Some);
+        }
+
+        if let Some(upvalue) = enclosing.resolve_upvalue(name)? {
+            return self.add_upvalue(upvalue, false).map(Some);
+        }
+
+        Ok(None)
+    }
+}
 
 //Define the Parser
+// it owns the scanner and borrows the VM's heap for the whole compilation,
+// so the parse functions don't have to pass them around
 #[derive(Debug)]
-pub struct Parser {
+pub struct Parser<'a> {
+    source: &'a str,
+    scanner: scanner::Scanner,
+    heap: &'a mut Heap,
+    // what the VM keeps alive, it can't change while compiling
+    vm_roots: Vec<Value>,
+    compiler: Compiler,
+    class_compiler: Option<Box<ClassCompiler>>,
     previous_token: Option<scanner::Token>,
     current_token: Option<scanner::Token>,
     had_error: bool,
+    // every error found so far, in source order
+    diagnostics: Vec<Diagnostic>,
     // set the panic mode to supress other errors
     panic_mode: bool,
 }
 
-impl Parser {
+impl<'a> Parser<'a> {
     // remember, parser.current is a token!
     // so parser.current.start is a valid statement
 
-    pub fn init_parser() -> Parser {
+    pub fn init_parser(source: &'a str, heap: &'a mut Heap, vm_roots: Vec<Value>) -> Parser<'a> {
         Parser {
+            source,
+            scanner: scanner::Scanner::init_scanner(),
+            heap,
+            vm_roots,
+            compiler: Compiler::init_compiler(FunctionType::Script, None),
+            class_compiler: None,
             previous_token: None,
             current_token: None,
             had_error: false,
+            diagnostics: Vec::<Diagnostic>::new(),
             panic_mode: false,
         }
     }
 
-    fn advance(&mut self, source: &str, scanner: &mut scanner::Scanner, chunk: &mut Chunk) {
-        self.previous_token = match &self.current_token {
-            Some(x) => Some(x.to_owned()),
-            None => None,
-        };
+    // the functions being compiled aren't in the heap yet, their constants are rooted here
+    fn collect_garbage(&mut self) {
+        for root in self.vm_roots.iter() {
+            self.heap.mark_value(*root);
+        }
+        let mut compiler = Some(&self.compiler);
+        while let Some(x) = compiler {
+            for constant in x.function.chunk.constants.values.iter() {
+                self.heap.mark_value(*constant);
+            }
+            if let Some(name) = x.function.name {
+                self.heap.mark_object(name);
+            }
+            compiler = x.enclosing.as_deref();
+        }
+        self.heap.collect_garbage();
+    }
+
+    fn copy_string(&mut self, chars: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.copy_string(chars)
+    }
+
+    fn current_chunk(&mut self) -> &mut Chunk {
+        &mut self.compiler.function.chunk
+    }
+
+    fn advance(&mut self) {
+        self.previous_token = self.current_token.to_owned();
 
         loop {
-            self.current_token = Some(scanner.scan_token(source));
-            match &self.current_token {
-                Some(x) => match x.kind {
-                    scanner::TokenKind::TokenError => {
-                        self.error_at_current("Error encountered passing through to advance");
-                    }
-                    _ => break,
-                },
-                None => {
-                    // Do nothing
-                }
+            let token = self.scanner.scan_token(self.source);
+            let message = token.message;
+            let is_error = token.kind == scanner::TokenKind::TokenError;
+            self.current_token = Some(token);
+            if !is_error {
+                break;
             }
+            // the scanner knows best what is wrong with the token
+            self.error_at_current(message.unwrap_or("Unexpected character."));
         }
     }
 
-    fn consume(
-        &mut self,
-        source: &str,
-        token_kind: scanner::TokenKind,
-        msg: &str,
-        scanner: &mut scanner::Scanner,
-        chunk: &mut Chunk,
-    ) {
+    fn consume(&mut self, token_kind: scanner::TokenKind, msg: &str) {
+        if self.check(token_kind) {
+            self.advance();
+        } else {
+            self.error_at_current(msg);
+        }
+    }
+
+    fn check(&self, token_kind: scanner::TokenKind) -> bool {
         match &self.current_token {
-            Some(x) => match &x.kind {
-                token_kind => {
-                    self.advance(source, scanner, chunk);
-                    return;
-                }
-            },
-            None => {
-                eprintln!("None in consume")
-            }
+            Some(x) => x.kind == token_kind,
+            None => false,
+        }
+    }
+
+    fn match_token(&mut self, token_kind: scanner::TokenKind) -> bool {
+        if !self.check(token_kind) {
+            return false;
         }
+        self.advance();
+        true
     }
 
     fn error_at_current(&mut self, message: &str) {
-        self.error_at("current", message);
+        let token = self.current_token.to_owned().unwrap();
+        self.error_at(&token, message, None);
     }
 
     fn error_at_prev(&mut self, message: &str) {
-        self.error_at("previous", message);
+        let token = self.previous_token.to_owned().unwrap();
+        self.error_at(&token, message, None);
     }
 
-    fn error_at(&mut self, tok: &str, message: &str) {
+    fn error_at_prev_with_note(&mut self, message: &str, note: &str) {
+        let token = self.previous_token.to_owned().unwrap();
+        self.error_at(&token, message, Some(note));
+    }
+
+    // in panic mode every further error is dropped until synchronize() finds
+    // the next statement, they are most likely caused by the first one
+    fn error_at(&mut self, token: &scanner::Token, message: &str, note: Option<&str>) {
         if self.panic_mode {
             return;
         }
         self.panic_mode = true;
-        eprintln!(
-            "{}",
-            format!("[line {}] Error", self.current_token.as_ref().unwrap().line)
-        );
-        match tok {
-            "current" => match &self.current_token {
-                Some(x) => match x.kind {
-                    scanner::TokenKind::TokenEof => {
-                        eprintln!(" at the end of the source code.");
-                    }
-                    scanner::TokenKind::TokenError => {}
-                    _ => {
-                        eprintln!(
-                            "{}",
-                            format!(" at col {} to {}", x.start, x.start + x.length)
-                        );
-                    }
-                },
-                None => {}
-            },
-            "previous" => match &self.current_token {
-                Some(x) => match &x.kind {
-                    scanner::TokenKind::TokenEof => {
-                        eprintln!(" at the end of the source code.");
-                    }
-                    scanner::TokenKind::TokenError => {}
-                    _ => {
-                        eprintln!(
-                            "{}",
-                            format!(" at col {} to {}", x.start, x.start + x.length)
-                        );
-                    }
-                },
-                None => {}
-            },
-            _ => {
-                eprintln!("unreachable state in error_At")
-            }
+        let mut diagnostic = Diagnostic::error(message, self.span(token));
+        if let Some(note) = note {
+            diagnostic = diagnostic.with_note(note);
         }
-        eprintln!("{}", format!(" :{}", message));
+        self.diagnostics.push(diagnostic);
         self.had_error = true;
     }
 
-    fn number(&self, source: &str, chunk: &mut Chunk) {
-        match self.previous_token.to_owned() {
-            Some(x) => {
-                let value = source.get(x.start..=x.start + x.length - 1).unwrap();
-                let cons = f64::from_str(value);
-                match cons {
-                    Ok(y) => {
-                        let mut byte = chunk.add_constant(y);
-                        // println!("{:?}", byte);
-                        self.emit_byte(chunk, byte);
-                    }
-                    Err(..) => {
-                        eprintln!("float conversion error");
-                    }
-                }
+    // skips tokens until something that looks like the start of a statement
+    fn synchronize(&mut self) {
+        self.panic_mode = false;
+        while !self.check(scanner::TokenKind::TokenEof) {
+            if self.previous_token.as_ref().map(|x| &x.kind)
+                == Some(&scanner::TokenKind::TokenSemiColon)
+            {
+                return;
+            }
+            let at_statement = matches!(
+                self.current_token.as_ref().map(|x| &x.kind),
+                Some(
+                    scanner::TokenKind::TokenClass
+                        | scanner::TokenKind::TokenFun
+                        | scanner::TokenKind::TokenVar
+                        | scanner::TokenKind::TokenFor
+                        | scanner::TokenKind::TokenIf
+                        | scanner::TokenKind::TokenWhile
+                        | scanner::TokenKind::TokenPrint
+                        | scanner::TokenKind::TokenReturn
+                )
+            );
+            if at_statement {
+                return;
+            }
+            self.advance();
+        }
+    }
+
+    fn number(&mut self) {
+        let token = self.previous_token.to_owned().unwrap();
+        match f64::from_str(self.lexeme(&token)) {
+            Ok(x) => self.emit_constant(Value::number(x)),
+            Err(..) => self.error_at_prev("Invalid number literal."),
+        }
+    }
+
+    fn string(&mut self) {
+        let token = self.previous_token.to_owned().unwrap();
+        // trim the surrounding quotes
+        let chars = self
+            .source
+            .get(token.start + 1..token.start + token.length - 1)
+            .unwrap();
+        let string = self.copy_string(chars);
+        self.emit_constant(Value::obj(string));
+    }
+
+    // the characters of the token in the source
+    fn lexeme(&self, token: &scanner::Token) -> &'a str {
+        self.source
+            .get(token.start..token.start + token.length)
+            .unwrap()
+    }
+
+    // global, property and method names are string constants, so the VM can look them up at runtime
+    fn identifier_constant(&mut self, name: &str) -> usize {
+        let name = self.copy_string(name);
+        if let Some(constant) = self.compiler.names.get(&name) {
+            return *constant;
+        }
+        let constant = self.make_constant(Value::obj(name));
+        self.compiler.names.insert(name, constant);
+        constant
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let token = self.previous_token.to_owned().unwrap();
+        self.named_variable(self.lexeme(&token), can_assign);
+    }
+
+    fn named_variable(&mut self, name: &str, can_assign: bool) {
+        // an assignment is reported at the variable, not at the end of its value
+        let token = self.previous_token.to_owned().unwrap();
+        // locals and upvalues are numbered below 256, only globals name a constant
+        let (get_op, set_op, arg, is_global) = if let Some(slot) = self.resolve_local(name) {
+            (OpCode::OpGetLocal, OpCode::OpSetLocal, slot, false)
+        } else if let Some(index) = self.resolve_upvalue(name) {
+            (OpCode::OpGetUpvalue, OpCode::OpSetUpvalue, index, false)
+        } else {
+            let name = self.identifier_constant(name);
+            (OpCode::OpGetGlobal, OpCode::OpSetGlobal, name, true)
+        };
+        let op_code = if can_assign && self.match_token(scanner::TokenKind::TokenEqual) {
+            self.expression();
+            set_op
+        } else {
+            get_op
+        };
+        if is_global {
+            self.emit_constant_op_at(op_code, arg, &token);
+        } else {
+            self.emit_byte_at(op_code, &token);
+            self.emit_byte_at(arg as u8, &token);
+        }
+    }
+
+    fn resolve_local(&mut self, name: &str) -> Option<usize> {
+        match self.compiler.resolve_local(name) {
+            Ok(slot) => slot,
+            Err(message) => {
+                self.error_at_prev(message);
+                None
+            }
+        }
+    }
+
+    fn resolve_upvalue(&mut self, name: &str) -> Option<usize> {
+        match self.compiler.resolve_upvalue(name) {
+            Ok(index) => index,
+            Err(message) => {
+                self.error_at_prev(message);
+                None
+            }
+        }
+    }
+
+    fn add_local(&mut self, name: String) {
+        if self.compiler.locals.len() == LOCALS_MAX {
+            self.error_at_prev("Too many local variables in function.");
+            return;
+        }
+        self.compiler.locals.push(Local {
+            name,
+            depth: -1,
+            is_captured: false,
+        });
+    }
+
+    fn declare_variable(&mut self) {
+        // globals are late bound, nothing to record here
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+        let token = self.previous_token.to_owned().unwrap();
+        let name = self.lexeme(&token);
+        let mut redeclared = false;
+        for local in self.compiler.locals.iter().rev() {
+            if local.depth != -1 && local.depth < self.compiler.scope_depth {
+                break;
             }
-            None => {
-                eprintln!("Exception in number()");
+            if local.name == name {
+                redeclared = true;
+                break;
             }
         }
+        if redeclared {
+            self.error_at_prev("Already a variable with this name in this scope.");
+        }
+        self.add_local(name.to_owned());
+    }
+
+    fn mark_initialized(&mut self) {
+        // a global function has no local slot to mark
+        if self.compiler.scope_depth == 0 {
+            return;
+        }
+        if let Some(local) = self.compiler.locals.last_mut() {
+            local.depth = self.compiler.scope_depth;
+        }
+    }
+
+    fn literal(&mut self) {
+        match self.previous_token.to_owned().unwrap().kind {
+            scanner::TokenKind::TokenFalse => self.emit_byte(OpCode::OpFalse),
+            scanner::TokenKind::TokenTrue => self.emit_byte(OpCode::OpTrue),
+            scanner::TokenKind::TokenNil => self.emit_byte(OpCode::OpNil),
+            _ => {}
+        }
     }
 
-    fn parse_precedence(
-        &mut self,
-        precede: u8,
-        source: &str,
-        scanner: &mut scanner::Scanner,
-        chunk: &mut Chunk,
-    ) {
-        self.advance(source, scanner, chunk);
+    fn parse_precedence(&mut self, precede: u8) {
+        self.advance();
         let owner = self.previous_token.to_owned().unwrap().kind;
         let (prefix, _, _) = parse_rule(owner);
+        // only the lowest precedence expressions may be assignment targets,
+        // this keeps `a + b = c` from assigning to b
+        let can_assign = precede <= PREC_ASSIGNMENT;
         match prefix {
             "none" => self.error_at_prev("Expect expression."),
-            "unary" => self.unary(source, scanner, chunk),
-            "grouping" => self.grouping(source, scanner, chunk),
-            "number" => self.number(source, chunk),
+            "unary" => self.unary(),
+            "grouping" => self.grouping(),
+            "number" => self.number(),
+            "literal" => self.literal(),
+            "string" => self.string(),
+            "variable" => self.variable(can_assign),
+            "this" => self.this(),
+            "super" => self.super_(),
             _ => self.error_at_prev("This is not a valid token"),
         }
         loop {
@@ -188,102 +484,746 @@ impl Parser {
                 break;
             }
 
-            self.advance(source, scanner, chunk);
+            self.advance();
 
             match infix {
-                "none" => {
-                    // self.error_at_current("Exception in parse_precedence");
+                "binary" => self.binary(),
+                "and" => self.and(),
+                "or" => self.or(),
+                "call" => self.call(),
+                "dot" => self.dot(can_assign),
+                _ => {}
+            }
+        }
+
+        if can_assign && self.match_token(scanner::TokenKind::TokenEqual) {
+            self.error_at_prev_with_note(
+                "Invalid assignment target.",
+                "only variables and fields can be assigned to",
+            );
+        }
+    }
+
+    // where the token sits in the source, columns are counted from the start of its line
+    fn span(&self, token: &scanner::Token) -> Span {
+        let line_start = self.source[..token.start].rfind('\n').map_or(0, |x| x + 1);
+        Span {
+            line: token.line,
+            column: self.source[line_start..token.start].chars().count() + 1,
+            length: token.length,
+        }
+    }
+
+    // takes opcodes as well as raw operand bytes
+    fn emit_byte(&mut self, byte: impl Into<u8>) {
+        let token = self.previous_token.to_owned().unwrap();
+        self.emit_byte_at(byte, &token);
+    }
+
+    // for instructions that can fail at runtime, so the error points at the
+    // operator rather than at whatever was parsed last
+    fn emit_byte_at(&mut self, byte: impl Into<u8>, token: &scanner::Token) {
+        let span = self.span(token);
+        self.current_chunk().write_chunk(byte.into(), span);
+    }
+
+    fn emit_bytes(&mut self, first: impl Into<u8>, second: impl Into<u8>) {
+        self.emit_byte(first);
+        self.emit_byte(second);
+    }
+
+    // constants referenced by an instruction's operand, like names and functions
+    fn make_constant(&mut self, value: Value) -> usize {
+        let constant = self.current_chunk().add_constant(value);
+        if constant >= 1 << 24 {
+            self.error_at_prev("Too many constants in one chunk.");
+            return 0;
+        }
+        constant
+    }
+
+    // past the first 256 constants the operand no longer fits in a byte,
+    // an OpWide prefix makes the instruction read a three byte one instead
+    fn emit_constant_op_at(&mut self, op_code: OpCode, constant: usize, token: &scanner::Token) {
+        if constant <= u8::MAX as usize {
+            self.emit_byte_at(op_code, token);
+            self.emit_byte_at(constant as u8, token);
+        } else {
+            self.emit_byte_at(OpCode::OpWide, token);
+            self.emit_byte_at(op_code, token);
+            self.emit_byte_at((constant >> 16) as u8, token);
+            self.emit_byte_at((constant >> 8) as u8, token);
+            self.emit_byte_at(constant as u8, token);
+        }
+    }
+
+    fn emit_constant_op(&mut self, op_code: OpCode, constant: usize) {
+        let token = self.previous_token.to_owned().unwrap();
+        self.emit_constant_op_at(op_code, constant, &token);
+    }
+
+    // literals switch to a three byte index once the first 256 constants are used up
+    fn emit_constant(&mut self, value: Value) {
+        let constant = self.current_chunk().add_constant(value);
+        if constant <= u8::MAX as usize {
+            self.emit_bytes(OpCode::OpConstant, constant as u8);
+        } else if constant < 1 << 24 {
+            self.emit_byte(OpCode::OpConstantLong);
+            self.emit_bytes((constant >> 16) as u8, (constant >> 8) as u8);
+            self.emit_byte(constant as u8);
+        } else {
+            self.error_at_prev("Too many constants in one chunk.");
+        }
+    }
+
+    // emits a jump with a placeholder offset, returns where the offset sits so it can be patched
+    fn emit_jump(&mut self, instruction: OpCode) -> usize {
+        self.emit_byte(instruction);
+        self.emit_bytes(0xffu8, 0xffu8);
+        self.current_chunk().code.len() - 2
+    }
+
+    fn patch_jump(&mut self, offset: usize) {
+        // -2 as the VM has already read the offset itself when it applies it
+        let jump = self.current_chunk().code.len() - offset - 2;
+        if jump > u16::MAX as usize {
+            self.error_at_prev("Too much code to jump over.");
+        }
+        self.current_chunk().code[offset] = (jump >> 8) as u8;
+        self.current_chunk().code[offset + 1] = jump as u8;
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::OpLoop);
+        // +2 to also jump back over the OpLoop's own offset
+        let offset = self.current_chunk().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error_at_prev("Loop body too large.");
+        }
+        self.emit_bytes((offset >> 8) as u8, offset as u8);
+    }
+
+    // a function without a return statement implicitly returns nil
+    // an initializer always returns the instance, which sits in slot zero
+    fn emit_return(&mut self) {
+        if self.compiler.kind == FunctionType::Initializer {
+            self.emit_bytes(OpCode::OpGetLocal, 0u8);
+        } else {
+            self.emit_byte(OpCode::OpNil);
+        }
+        self.emit_byte(OpCode::OpReturn);
+    }
+
+    // finishes the innermost function and hands the enclosing compiler back,
+    // along with the variables the function captures from it
+    fn end_compiler(&mut self) -> (ObjFunction, Vec<Upvalue>) {
+        self.emit_return();
+        let enclosing = self.compiler.enclosing.take();
+        let compiler = match enclosing {
+            Some(enclosing) => std::mem::replace(&mut self.compiler, *enclosing),
+            None => std::mem::replace(
+                &mut self.compiler,
+                Compiler::init_compiler(FunctionType::Script, None),
+            ),
+        };
+        let function = compiler.function;
+        let upvalues = compiler.upvalues;
+        (function, upvalues)
+    }
+
+    fn expression(&mut self) {
+        self.parse_precedence(PREC_ASSIGNMENT);
+    }
+
+    fn unary(&mut self) {
+        let operator = self.previous_token.to_owned().unwrap();
+        self.parse_precedence(PREC_UNARY);
+
+        match operator.kind {
+            scanner::TokenKind::TokenMinus => self.emit_byte_at(OpCode::OpNegate, &operator),
+            scanner::TokenKind::TokenBang => self.emit_byte_at(OpCode::OpNot, &operator),
+            _ => {}
+        }
+    }
+
+    fn binary(&mut self) {
+        let operator = self.previous_token.to_owned().unwrap();
+        let (_, _, prec) = parse_rule(operator.kind.to_owned());
+        self.parse_precedence(prec + 1);
+
+        let (op_code, negate) = match operator.kind {
+            scanner::TokenKind::TokenPlus => (OpCode::OpAdd, false),
+            scanner::TokenKind::TokenMinus => (OpCode::OpSubtract, false),
+            scanner::TokenKind::TokenSlash => (OpCode::OpDivide, false),
+            scanner::TokenKind::TokenStar => (OpCode::OpMultiply, false),
+            scanner::TokenKind::TokenBangEqual => (OpCode::OpEqual, true),
+            scanner::TokenKind::TokenEqualEqual => (OpCode::OpEqual, false),
+            scanner::TokenKind::TokenGreater => (OpCode::OpGreater, false),
+            scanner::TokenKind::TokenGreaterEqual => (OpCode::OpLess, true),
+            scanner::TokenKind::TokenLess => (OpCode::OpLess, false),
+            scanner::TokenKind::TokenLessEqual => (OpCode::OpGreater, true),
+            _ => return,
+        };
+        self.emit_byte_at(op_code, &operator);
+        if negate {
+            self.emit_byte_at(OpCode::OpNot, &operator);
+        }
+    }
+
+    fn declaration(&mut self) {
+        if self.match_token(scanner::TokenKind::TokenClass) {
+            self.class_declaration();
+        } else if self.match_token(scanner::TokenKind::TokenFun) {
+            self.fun_declaration();
+        } else if self.match_token(scanner::TokenKind::TokenVar) {
+            self.var_declaration();
+        } else {
+            self.statement();
+        }
+
+        if self.panic_mode {
+            self.synchronize();
+        }
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(scanner::TokenKind::TokenIdentifier, "Expect class name.");
+        let class_token = self.previous_token.to_owned().unwrap();
+        let class_name = self.lexeme(&class_token);
+        let name_constant = self.identifier_constant(class_name);
+        self.declare_variable();
+
+        self.emit_constant_op(OpCode::OpClass, name_constant);
+        if self.compiler.scope_depth > 0 {
+            self.define_variable(None);
+        } else {
+            self.define_variable(Some(name_constant));
+        }
+
+        let class_compiler = ClassCompiler {
+            enclosing: self.class_compiler.take(),
+            has_superclass: false,
+        };
+        self.class_compiler = Some(Box::new(class_compiler));
+
+        if self.match_token(scanner::TokenKind::TokenLess) {
+            self.consume(
+                scanner::TokenKind::TokenIdentifier,
+                "Expect superclass name.",
+            );
+            self.variable(false);
+
+            let superclass_token = self.previous_token.to_owned().unwrap();
+            if self.lexeme(&superclass_token) == class_name {
+                self.error_at_prev("A class can't inherit from itself.");
+            }
+
+            // the superclass lives in a local named `super` in a scope around the
+            // methods, so every method closes over it as an upvalue
+            self.begin_scope();
+            self.add_local("super".to_owned());
+            self.define_variable(None);
+
+            self.named_variable(class_name, false);
+            self.emit_byte(OpCode::OpInherit);
+            self.class_compiler.as_mut().unwrap().has_superclass = true;
+        }
+
+        // the methods are attached to the class sitting on top of the stack
+        self.named_variable(class_name, false);
+        self.consume(
+            scanner::TokenKind::TokenLeftBrace,
+            "Expect '{' before class body.",
+        );
+        while !self.check(scanner::TokenKind::TokenRightBrace)
+            && !self.check(scanner::TokenKind::TokenEof)
+        {
+            self.method();
+        }
+        self.consume(
+            scanner::TokenKind::TokenRightBrace,
+            "Expect '}' after class body.",
+        );
+        self.emit_byte(OpCode::OpPop);
+
+        let class_compiler = self.class_compiler.take().unwrap();
+        if class_compiler.has_superclass {
+            self.end_scope();
+        }
+        self.class_compiler = class_compiler.enclosing;
+    }
+
+    fn method(&mut self) {
+        self.consume(scanner::TokenKind::TokenIdentifier, "Expect method name.");
+        let token = self.previous_token.to_owned().unwrap();
+        let name = self.lexeme(&token);
+        let constant = self.identifier_constant(name);
+
+        let kind = if name == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(kind);
+        self.emit_constant_op(OpCode::OpMethod, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        // a function may refer to itself, so it is initialized before its body is compiled
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, kind: FunctionType) {
+        let token = self.previous_token.to_owned().unwrap();
+        let chars = self
+            .source
+            .get(token.start..token.start + token.length)
+            .unwrap();
+        let name = self.copy_string(chars);
+
+        let compiler = Compiler::init_compiler(kind, Some(name));
+        let enclosing = std::mem::replace(&mut self.compiler, compiler);
+        self.compiler.enclosing = Some(Box::new(enclosing));
+        // no end_scope, the whole frame is thrown away when the function returns
+        self.begin_scope();
+
+        self.consume(
+            scanner::TokenKind::TokenLeftParen,
+            "Expect '(' after function name.",
+        );
+        if !self.check(scanner::TokenKind::TokenRightParen) {
+            loop {
+                self.compiler.function.arity += 1;
+                if self.compiler.function.arity > ARGS_MAX {
+                    self.error_at_current("Can't have more than 255 parameters.");
                 }
-                "binary" => {
-                    self.binary(source, scanner, chunk);
+                let param = self.parse_variable("Expect parameter name.");
+                self.define_variable(param);
+                if !self.match_token(scanner::TokenKind::TokenComma) {
+                    break;
                 }
-                _ => {}
             }
         }
+        self.consume(
+            scanner::TokenKind::TokenRightParen,
+            "Expect ')' after parameters.",
+        );
+        self.consume(
+            scanner::TokenKind::TokenLeftBrace,
+            "Expect '{' before function body.",
+        );
+        self.block();
+
+        let (function, upvalues) = self.end_compiler();
+        // out of the compiler chain now, so allocate it without collecting
+        let function = self.heap.alloc(Obj::Function(function));
+        let constant = self.make_constant(Value::obj(function));
+        self.emit_constant_op(OpCode::OpClosure, constant);
+        // the descriptors follow the closure instruction as its operands
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local as u8, upvalue.index as u8);
+        }
     }
 
-    fn emit_byte(&self, chunk: &mut Chunk, byte: OpCode) {
-        chunk.write_chunk(byte, self.previous_token.as_ref().unwrap().line);
+    fn var_declaration(&mut self) {
+        let global = self.parse_variable("Expect variable name.");
+
+        if self.match_token(scanner::TokenKind::TokenEqual) {
+            self.expression();
+        } else {
+            // var a; is the same as var a = nil;
+            self.emit_byte(OpCode::OpNil);
+        }
+        self.consume(
+            scanner::TokenKind::TokenSemiColon,
+            "Expect ';' after variable declaration.",
+        );
+
+        self.define_variable(global);
     }
 
-    fn emit_return(&self, chunk: &mut Chunk) {
-        self.emit_byte(chunk, OpCode::OpReturn);
+    // returns the name constant for globals, locals don't need one
+    fn parse_variable(&mut self, message: &str) -> Option<usize> {
+        self.consume(scanner::TokenKind::TokenIdentifier, message);
+
+        self.declare_variable();
+        if self.compiler.scope_depth > 0 {
+            return None;
+        }
+
+        let token = self.previous_token.to_owned().unwrap();
+        Some(self.identifier_constant(self.lexeme(&token)))
     }
 
-    fn expression(&mut self, source: &str, scanner: &mut scanner::Scanner, chunk: &mut Chunk) {
-        self.parse_precedence(PREC_ASSIGNMENT, source, scanner, chunk);
+    fn define_variable(&mut self, global: Option<usize>) {
+        match global {
+            Some(name) => self.emit_constant_op(OpCode::OpDefineGlobal, name),
+            // the initializer's value is already sitting in the local's slot
+            None => self.mark_initialized(),
+        }
     }
 
-    fn unary(&mut self, source: &str, scanner: &mut scanner::Scanner, chunk: &mut Chunk) {
-        let token_kind = self.previous_token.to_owned().unwrap().kind;
-        self.parse_precedence(PREC_UNARY, source, scanner, chunk);
+    fn statement(&mut self) {
+        if self.match_token(scanner::TokenKind::TokenPrint) {
+            self.print_statement();
+        } else if self.match_token(scanner::TokenKind::TokenIf) {
+            self.if_statement();
+        } else if self.match_token(scanner::TokenKind::TokenReturn) {
+            self.return_statement();
+        } else if self.match_token(scanner::TokenKind::TokenWhile) {
+            self.while_statement();
+        } else if self.match_token(scanner::TokenKind::TokenFor) {
+            self.for_statement();
+        } else if self.match_token(scanner::TokenKind::TokenLeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement();
+        }
+    }
+
+    fn block(&mut self) {
+        while !self.check(scanner::TokenKind::TokenRightBrace)
+            && !self.check(scanner::TokenKind::TokenEof)
+        {
+            self.declaration();
+        }
+        self.consume(
+            scanner::TokenKind::TokenRightBrace,
+            "Expect '}' after block.",
+        );
+    }
+
+    fn begin_scope(&mut self) {
+        self.compiler.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.compiler.scope_depth -= 1;
+        // pop the locals that go out of scope off the VM stack
+        while let Some(local) = self.compiler.locals.last() {
+            if local.depth <= self.compiler.scope_depth {
+                break;
+            }
+            if local.is_captured {
+                self.emit_byte(OpCode::OpCloseUpvalue);
+            } else {
+                self.emit_byte(OpCode::OpPop);
+            }
+            self.compiler.locals.pop();
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(scanner::TokenKind::TokenLeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(
+            scanner::TokenKind::TokenRightParen,
+            "Expect ')' after condition.",
+        );
+
+        // the condition stays on the stack, each branch pops it
+        let then_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+        self.emit_byte(OpCode::OpPop);
+        self.statement();
 
-        if token_kind == scanner::TokenKind::TokenMinus {
-            self.emit_byte(chunk, OpCode::OpNegate);
+        let else_jump = self.emit_jump(OpCode::OpJump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::OpPop);
+
+        if self.match_token(scanner::TokenKind::TokenElse) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.current_chunk().code.len();
+        self.consume(
+            scanner::TokenKind::TokenLeftParen,
+            "Expect '(' after 'while'.",
+        );
+        self.expression();
+        self.consume(
+            scanner::TokenKind::TokenRightParen,
+            "Expect ')' after condition.",
+        );
+
+        let exit_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+        self.emit_byte(OpCode::OpPop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::OpPop);
+    }
+
+    fn for_statement(&mut self) {
+        // the initializer's variable is scoped to the loop
+        self.begin_scope();
+        self.consume(
+            scanner::TokenKind::TokenLeftParen,
+            "Expect '(' after 'for'.",
+        );
+        if self.match_token(scanner::TokenKind::TokenSemiColon) {
+            // no initializer
+        } else if self.match_token(scanner::TokenKind::TokenVar) {
+            self.var_declaration();
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.current_chunk().code.len();
+        let mut exit_jump: Option<usize> = None;
+        if !self.match_token(scanner::TokenKind::TokenSemiColon) {
+            self.expression();
+            self.consume(
+                scanner::TokenKind::TokenSemiColon,
+                "Expect ';' after loop condition.",
+            );
+
+            exit_jump = Some(self.emit_jump(OpCode::OpJumpIfFalse));
+            self.emit_byte(OpCode::OpPop);
+        }
+
+        // the increment is compiled before the body but runs after it,
+        // so jump over it now and loop back to it from the end of the body
+        if !self.match_token(scanner::TokenKind::TokenRightParen) {
+            let body_jump = self.emit_jump(OpCode::OpJump);
+            let increment_start = self.current_chunk().code.len();
+            self.expression();
+            self.emit_byte(OpCode::OpPop);
+            self.consume(
+                scanner::TokenKind::TokenRightParen,
+                "Expect ')' after for clauses.",
+            );
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::OpPop);
+        }
+
+        self.end_scope();
+    }
+
+    fn return_statement(&mut self) {
+        if self.compiler.kind == FunctionType::Script {
+            self.error_at_prev("Can't return from top-level code.");
+        }
+
+        if self.match_token(scanner::TokenKind::TokenSemiColon) {
+            self.emit_return();
+        } else {
+            if self.compiler.kind == FunctionType::Initializer {
+                self.error_at_prev_with_note(
+                    "Can't return a value from an initializer.",
+                    "init() always returns the new instance",
+                );
+            }
+            self.expression();
+            self.consume(
+                scanner::TokenKind::TokenSemiColon,
+                "Expect ';' after return value.",
+            );
+            self.emit_byte(OpCode::OpReturn);
+        }
+    }
+
+    fn print_statement(&mut self) {
+        self.expression();
+        self.consume(
+            scanner::TokenKind::TokenSemiColon,
+            "Expect ';' after value.",
+        );
+        self.emit_byte(OpCode::OpPrint);
+    }
+
+    // evaluate the expression for its side effects and throw the result away
+    fn expression_statement(&mut self) {
+        self.expression();
+        self.consume(
+            scanner::TokenKind::TokenSemiColon,
+            "Expect ';' after expression.",
+        );
+        self.emit_byte(OpCode::OpPop);
+    }
+
+    // the left operand is on the stack, skip the right one if it is falsey
+    fn and(&mut self) {
+        let end_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+
+        self.emit_byte(OpCode::OpPop);
+        self.parse_precedence(PREC_AND);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self) {
+        let else_jump = self.emit_jump(OpCode::OpJumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::OpJump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::OpPop);
+
+        self.parse_precedence(PREC_OR);
+        self.patch_jump(end_jump);
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(
+            scanner::TokenKind::TokenIdentifier,
+            "Expect property name after '.'.",
+        );
+        let token = self.previous_token.to_owned().unwrap();
+        let name = self.identifier_constant(self.lexeme(&token));
+
+        if can_assign && self.match_token(scanner::TokenKind::TokenEqual) {
+            self.expression();
+            self.emit_constant_op_at(OpCode::OpSetProperty, name, &token);
+        } else if self.match_token(scanner::TokenKind::TokenLeftParen) {
+            // calling a method right away skips creating a bound method
+            let arg_count = self.argument_list();
+            self.emit_constant_op_at(OpCode::OpInvoke, name, &token);
+            self.emit_byte_at(arg_count, &token);
         } else {
+            self.emit_constant_op(OpCode::OpGetProperty, name);
+        }
+    }
+
+    fn this(&mut self) {
+        if self.class_compiler.is_none() {
+            self.error_at_prev("Can't use 'this' outside of a class.");
             return;
         }
+        // `this` is an ordinary local in slot zero, it can't be assigned to
+        self.variable(false);
     }
 
-    fn binary(&mut self, source: &str, scanner: &mut scanner::Scanner, chunk: &mut Chunk) {
-        let token_kind = self.previous_token.to_owned().unwrap().kind;
-        let (prefix, infix, prec) = parse_rule(token_kind.to_owned());
-        self.parse_precedence(prec + 1, source, scanner, chunk);
+    fn super_(&mut self) {
+        match &self.class_compiler {
+            None => self.error_at_prev("Can't use 'super' outside of a class."),
+            Some(x) if !x.has_superclass => {
+                self.error_at_prev("Can't use 'super' in a class with no superclass.")
+            }
+            _ => {}
+        }
 
-        match token_kind {
-            scanner::TokenKind::TokenPlus => self.emit_byte(chunk, OpCode::OpAdd),
-            scanner::TokenKind::TokenMinus => self.emit_byte(chunk, OpCode::OpSubtract),
-            scanner::TokenKind::TokenSlash => self.emit_byte(chunk, OpCode::OpDivide),
-            scanner::TokenKind::TokenStar => self.emit_byte(chunk, OpCode::OpMultiply),
-            _ => return,
+        self.consume(scanner::TokenKind::TokenPeriod, "Expect '.' after 'super'.");
+        self.consume(
+            scanner::TokenKind::TokenIdentifier,
+            "Expect superclass method name.",
+        );
+        let token = self.previous_token.to_owned().unwrap();
+        let name = self.identifier_constant(self.lexeme(&token));
+
+        // the receiver goes below the superclass the method is looked up in
+        self.named_variable("this", false);
+        if self.match_token(scanner::TokenKind::TokenLeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable("super", false);
+            self.emit_constant_op(OpCode::OpSuperInvoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable("super", false);
+            self.emit_constant_op(OpCode::OpGetSuper, name);
+        }
+    }
+
+    fn call(&mut self) {
+        let paren = self.previous_token.to_owned().unwrap();
+        let arg_count = self.argument_list();
+        self.emit_byte_at(OpCode::OpCall, &paren);
+        self.emit_byte_at(arg_count, &paren);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count = 0;
+        if !self.check(scanner::TokenKind::TokenRightParen) {
+            loop {
+                self.expression();
+                if arg_count == ARGS_MAX {
+                    self.error_at_prev("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+                if !self.match_token(scanner::TokenKind::TokenComma) {
+                    break;
+                }
+            }
         }
+        self.consume(
+            scanner::TokenKind::TokenRightParen,
+            "Expect ')' after arguments.",
+        );
+        arg_count as u8
     }
 
-    fn grouping(&mut self, source: &str, scanner: &mut scanner::Scanner, chunk: &mut Chunk) {
-        self.expression(source, scanner, chunk);
+    fn grouping(&mut self) {
+        self.expression();
         self.consume(
-            source,
             scanner::TokenKind::TokenRightParen,
-            "exprected ')' after expression",
-            scanner,
-            chunk,
+            "Expect ')' after expression.",
         );
     }
 }
 
+// compiles the whole source into the top level script function
 pub fn compile(
     source: &str,
-    chunk: &mut Chunk,
-    parser: &mut Parser,
-    scanner: &mut scanner::Scanner,
-) -> bool {
-    parser.advance(source, scanner, chunk);
-    parser.expression(source, scanner, chunk);
-
-    // println!("{:?}", parser);
-
-    parser.consume(
-        source,
-        scanner::TokenKind::TokenEof,
-        "Expected end of expression in compile",
-        scanner,
-        chunk,
-    );
-    parser.emit_return(chunk);
-    if std::env::args().any(|x| x == "debug_build") {
-        disassemble_chunk(chunk, "Code");
-    }
-    !parser.had_error
+    heap: &mut Heap,
+    vm_roots: Vec<Value>,
+) -> Result<ObjRef, Vec<Diagnostic>> {
+    let mut parser = Parser::init_parser(source, heap, vm_roots);
+    parser.advance();
+
+    while !parser.match_token(scanner::TokenKind::TokenEof) {
+        parser.declaration();
+    }
+
+    let (function, _) = parser.end_compiler();
+    if parser.had_error {
+        return Err(parser.diagnostics);
+    }
+    Ok(parser.heap.alloc(Obj::Function(function)))
 }
 
 fn parse_rule(owner: scanner::TokenKind) -> (&'static str, &'static str, u8) {
     match owner {
-        scanner::TokenKind::TokenLeftParen => return ("grouping", "none", PREC_NONE),
-        scanner::TokenKind::TokenPlus => return ("none", "binary", PREC_TERM),
-        scanner::TokenKind::TokenMinus => return ("unary", "binary", PREC_TERM),
-        scanner::TokenKind::TokenSlash => return ("none", "binary", PREC_FACTOR),
-        scanner::TokenKind::TokenStar => return ("none", "binary", PREC_FACTOR),
-        scanner::TokenKind::TokenNumber => return ("number", "none", PREC_NONE),
-        _ => return ("none", "none", PREC_NONE),
+        scanner::TokenKind::TokenLeftParen => ("grouping", "call", PREC_CALL),
+        scanner::TokenKind::TokenPeriod => ("none", "dot", PREC_CALL),
+        scanner::TokenKind::TokenThis => ("this", "none", PREC_NONE),
+        scanner::TokenKind::TokenSuper => ("super", "none", PREC_NONE),
+        scanner::TokenKind::TokenPlus => ("none", "binary", PREC_TERM),
+        scanner::TokenKind::TokenMinus => ("unary", "binary", PREC_TERM),
+        scanner::TokenKind::TokenSlash => ("none", "binary", PREC_FACTOR),
+        scanner::TokenKind::TokenStar => ("none", "binary", PREC_FACTOR),
+        scanner::TokenKind::TokenBang => ("unary", "none", PREC_NONE),
+        scanner::TokenKind::TokenBangEqual => ("none", "binary", PREC_EQUALITY),
+        scanner::TokenKind::TokenEqualEqual => ("none", "binary", PREC_EQUALITY),
+        scanner::TokenKind::TokenGreater => ("none", "binary", PREC_COMPARISON),
+        scanner::TokenKind::TokenGreaterEqual => ("none", "binary", PREC_COMPARISON),
+        scanner::TokenKind::TokenLess => ("none", "binary", PREC_COMPARISON),
+        scanner::TokenKind::TokenLessEqual => ("none", "binary", PREC_COMPARISON),
+        scanner::TokenKind::TokenAnd => ("none", "and", PREC_AND),
+        scanner::TokenKind::TokenOr => ("none", "or", PREC_OR),
+        scanner::TokenKind::TokenIdentifier => ("variable", "none", PREC_NONE),
+        scanner::TokenKind::TokenString => ("string", "none", PREC_NONE),
+        scanner::TokenKind::TokenNumber => ("number", "none", PREC_NONE),
+        scanner::TokenKind::TokenFalse => ("literal", "none", PREC_NONE),
+        scanner::TokenKind::TokenTrue => ("literal", "none", PREC_NONE),
+        scanner::TokenKind::TokenNil => ("literal", "none", PREC_NONE),
+        _ => ("none", "none", PREC_NONE),
     }
 }
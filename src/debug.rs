@@ -1,62 +1,267 @@
-// this file is necessary because of the representation of OpCodes as bytes
-use crate::chunk::value;
+// this file is necessary because of the representation of OpCodes as bytes.
+// everything is written to the sink the caller passes in, usually the VM's output
+use std::io;
+use std::io::Write;
+
 use crate::chunk::Chunk;
-use crate::chunk::OpCode;
+use crate::chunk::Instruction;
+use crate::object::{Heap, Obj, ObjRef};
+use crate::table::Table;
+use crate::value;
 use crate::vm;
 
-pub fn disassemble_chunk(chunk: &Chunk, name: &str) {
-    println!("====={}=====", name);
+fn debug_build() -> bool {
+    std::env::args().any(|x| &x == "debug_build")
+}
+
+// disassembles a freshly compiled script and every function nested in it
+pub fn debug_script(out: &mut dyn Write, heap: &Heap, function: ObjRef) -> io::Result<()> {
+    if debug_build() {
+        disassemble_function(out, heap, function)?;
+    }
+    Ok(())
+}
+
+fn disassemble_function(out: &mut dyn Write, heap: &Heap, function: ObjRef) -> io::Result<()> {
+    let function = heap.as_function(function);
+    let name = match function.name {
+        Some(name) => heap.as_string(name).chars.to_owned(),
+        None => "<script>".to_owned(),
+    };
+    disassemble_chunk(out, &function.chunk, &name, heap)?;
+    for constant in function.chunk.constants.values.iter() {
+        if let value::Value::Obj(x) = *constant {
+            if let Obj::Function(_) = heap.get(x) {
+                disassemble_function(out, heap, x)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn disassemble_chunk(
+    out: &mut dyn Write,
+    chunk: &Chunk,
+    name: &str,
+    heap: &Heap,
+) -> io::Result<()> {
+    writeln!(out, "====={}=====", name)?;
     //for all instructions in the chunk, disassemble them
     let mut offset = 0;
     while offset < chunk.count {
-        offset = disassemble_instruction(chunk, offset);
+        offset = disassemble_instruction(out, chunk, offset, heap)?;
     }
+    Ok(())
 }
 
-pub fn disassemble_instruction(chunk: &Chunk, offset: usize) -> usize {
-    print!("---{}    ", offset);
-    if offset > 0 && chunk.lines[offset] == chunk.lines[offset - 1] {
+pub fn disassemble_instruction(
+    out: &mut dyn Write,
+    chunk: &Chunk,
+    offset: usize,
+    heap: &Heap,
+) -> io::Result<usize> {
+    write!(out, "---{}    ", offset)?;
+    let span = chunk.get_span(offset);
+    if offset > 0 && span.line == chunk.get_line(offset - 1) {
         //check if the last and the current line are same
-        print! {"    |   "};
+        write!(out, "    |:{}   ", span.column)?;
     } else {
         //new inst line
-        print!("    {}   ", chunk.lines[offset]);
+        write!(out, "    {}:{}   ", span.line, span.column)?;
     }
-    let inst = chunk.code[offset];
+    let (inst, next) = match chunk.decode(offset) {
+        Some(x) => x,
+        None => {
+            writeln!(out, "Unknown opcode {}", chunk.code[offset])?;
+            return Ok(offset + 1);
+        }
+    };
     match inst {
-        OpCode::OpConstant(x) => constant_instruction("OpConstant", x, offset),
-        OpCode::OpReturn => simple_instruction("OpReturn", offset),
-        OpCode::OpNegate => simple_instruction("OpNegate", offset),
-        OpCode::OpAdd => simple_instruction("OpAdd", offset),
-        OpCode::OpSubtract => simple_instruction("OpSubtract", offset),
-        OpCode::OpDivide => simple_instruction("OpDivide", offset),
-        OpCode::OpMultiply => simple_instruction("OpMultiply", offset),
+        Instruction::OpConstant(x) => constant_instruction(out, "OpConstant", chunk, x, next, heap),
+        Instruction::OpConstantLong(x) => {
+            constant_instruction(out, "OpConstantLong", chunk, x, next, heap)
+        }
+        Instruction::OpNil => simple_instruction(out, "OpNil", next),
+        Instruction::OpTrue => simple_instruction(out, "OpTrue", next),
+        Instruction::OpFalse => simple_instruction(out, "OpFalse", next),
+        Instruction::OpEqual => simple_instruction(out, "OpEqual", next),
+        Instruction::OpGreater => simple_instruction(out, "OpGreater", next),
+        Instruction::OpLess => simple_instruction(out, "OpLess", next),
+        Instruction::OpReturn => simple_instruction(out, "OpReturn", next),
+        Instruction::OpNegate => simple_instruction(out, "OpNegate", next),
+        Instruction::OpAdd => simple_instruction(out, "OpAdd", next),
+        Instruction::OpSubtract => simple_instruction(out, "OpSubtract", next),
+        Instruction::OpDivide => simple_instruction(out, "OpDivide", next),
+        Instruction::OpMultiply => simple_instruction(out, "OpMultiply", next),
+        Instruction::OpNot => simple_instruction(out, "OpNot", next),
+        Instruction::OpPrint => simple_instruction(out, "OpPrint", next),
+        Instruction::OpPop => simple_instruction(out, "OpPop", next),
+        Instruction::OpDefineGlobal(x) => {
+            constant_instruction(out, "OpDefineGlobal", chunk, x, next, heap)
+        }
+        Instruction::OpGetGlobal(x) => {
+            constant_instruction(out, "OpGetGlobal", chunk, x, next, heap)
+        }
+        Instruction::OpSetGlobal(x) => {
+            constant_instruction(out, "OpSetGlobal", chunk, x, next, heap)
+        }
+        Instruction::OpGetLocal(x) => byte_instruction(out, "OpGetLocal", x, next),
+        Instruction::OpSetLocal(x) => byte_instruction(out, "OpSetLocal", x, next),
+        Instruction::OpJump(x) => jump_instruction(out, "OpJump", 1, x, offset, next),
+        Instruction::OpJumpIfFalse(x) => jump_instruction(out, "OpJumpIfFalse", 1, x, offset, next),
+        Instruction::OpLoop(x) => jump_instruction(out, "OpLoop", -1, x, offset, next),
+        Instruction::OpCall(x) => byte_instruction(out, "OpCall", x, next),
+        Instruction::OpClosure(x) => closure_instruction(out, chunk, x, next, heap),
+        Instruction::OpGetUpvalue(x) => byte_instruction(out, "OpGetUpvalue", x, next),
+        Instruction::OpSetUpvalue(x) => byte_instruction(out, "OpSetUpvalue", x, next),
+        Instruction::OpCloseUpvalue => simple_instruction(out, "OpCloseUpvalue", next),
+        Instruction::OpClass(x) => constant_instruction(out, "OpClass", chunk, x, next, heap),
+        Instruction::OpGetProperty(x) => {
+            constant_instruction(out, "OpGetProperty", chunk, x, next, heap)
+        }
+        Instruction::OpSetProperty(x) => {
+            constant_instruction(out, "OpSetProperty", chunk, x, next, heap)
+        }
+        Instruction::OpMethod(x) => constant_instruction(out, "OpMethod", chunk, x, next, heap),
+        Instruction::OpInvoke(x, args) => {
+            invoke_instruction(out, "OpInvoke", chunk, x, args, next, heap)
+        }
+        Instruction::OpInherit => simple_instruction(out, "OpInherit", next),
+        Instruction::OpGetSuper(x) => constant_instruction(out, "OpGetSuper", chunk, x, next, heap),
+        Instruction::OpSuperInvoke(x, args) => {
+            invoke_instruction(out, "OpSuperInvoke", chunk, x, args, next, heap)
+        }
+    }
+}
+
+fn constant_instruction(
+    out: &mut dyn Write,
+    name: &str,
+    chunk: &Chunk,
+    constant: usize,
+    next: usize,
+    heap: &Heap,
+) -> io::Result<usize> {
+    let value = chunk.constants.values[constant];
+    writeln!(
+        out,
+        "{}   ---   {} '{}'",
+        name,
+        constant,
+        heap.display(value)
+    )?;
+    Ok(next)
+}
+
+fn invoke_instruction(
+    out: &mut dyn Write,
+    name: &str,
+    chunk: &Chunk,
+    constant: usize,
+    arg_count: usize,
+    next: usize,
+    heap: &Heap,
+) -> io::Result<usize> {
+    let method = chunk.constants.values[constant];
+    writeln!(
+        out,
+        "{}   ---   ({} args) {} '{}'",
+        name,
+        arg_count,
+        constant,
+        heap.display(method)
+    )?;
+    Ok(next)
+}
+
+// the closure is followed by a pair of bytes for every captured variable
+fn closure_instruction(
+    out: &mut dyn Write,
+    chunk: &Chunk,
+    constant: usize,
+    next: usize,
+    heap: &Heap,
+) -> io::Result<usize> {
+    let function = chunk.constants.values[constant];
+    writeln!(
+        out,
+        "OpClosure   ---   {} {}",
+        constant,
+        heap.display(function)
+    )?;
+    let upvalue_count = heap.as_function(function.as_obj().unwrap()).upvalue_count;
+    let mut offset = next;
+    for _ in 0..upvalue_count {
+        if let Some((is_local, index)) = chunk.decode_capture(offset) {
+            writeln!(
+                out,
+                "---{}        |                 {} {}",
+                offset,
+                if is_local { "local" } else { "upvalue" },
+                index
+            )?;
+        }
+        offset += 2;
     }
+    Ok(offset)
+}
+
+fn byte_instruction(
+    out: &mut dyn Write,
+    name: &str,
+    slot: usize,
+    next: usize,
+) -> io::Result<usize> {
+    writeln!(out, "{}   ---   {}", name, slot)?;
+    Ok(next)
 }
 
-fn constant_instruction(name: &str, value: value::Value, offset: usize) -> usize {
-    print!("{}   ---   {}", name, value);
-    println!();
-    offset + 1
+// prints where the jump lands instead of the raw distance
+fn jump_instruction(
+    out: &mut dyn Write,
+    name: &str,
+    sign: i64,
+    jump: usize,
+    offset: usize,
+    next: usize,
+) -> io::Result<usize> {
+    let target = next as i64 + sign * jump as i64;
+    writeln!(out, "{}   ---   {} -> {}", name, offset, target)?;
+    Ok(next)
 }
 
-pub fn debug_stack_trace(vm: &vm::VM) {
-    if std::env::args().any(|x| &x == "debug_build") {
-        for (_, value) in vm.stack.iter().enumerate() {
-            println!(" -- STACK TRACE -- ");
-            print!("[ ");
-            print_value(*value);
-            print!(" ]");
+pub fn debug_stack_trace(out: &mut dyn Write, vm: &vm::VM) -> io::Result<()> {
+    if debug_build() {
+        for value in vm.stack.iter() {
+            writeln!(out, " -- STACK TRACE -- ")?;
+            write!(out, "[ ")?;
+            print_value(out, *value, &vm.heap)?;
+            write!(out, " ]")?;
         }
-        println!("--- STACK TRACE ENDS ---");
+        writeln!(out, "--- STACK TRACE ENDS ---")?;
+    }
+    Ok(())
+}
+
+pub fn debug_table(out: &mut dyn Write, table: &Table, name: &str) -> io::Result<()> {
+    if debug_build() {
+        writeln!(
+            out,
+            "--- TABLE {} --- entries: {} capacity: {} load factor: {:.2}",
+            name,
+            table.count,
+            table.capacity(),
+            table.load_factor()
+        )?;
     }
+    Ok(())
 }
 
-pub fn print_value(value: value::Value) {
-    print!("{}", value);
+pub fn print_value(out: &mut dyn Write, value: value::Value, heap: &Heap) -> io::Result<()> {
+    write!(out, "{}", heap.display(value))
 }
 
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{}", name);
-    offset + 1
+fn simple_instruction(out: &mut dyn Write, name: &str, next: usize) -> io::Result<usize> {
+    writeln!(out, "{}", name)?;
+    Ok(next)
 }
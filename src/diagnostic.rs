@@ -0,0 +1,61 @@
+// Problems found while compiling. The compiler collects them instead of
+// printing, so one run can report every error and the caller decides how
+// to show them
+use std::fmt;
+
+use crate::chunk::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // the token the problem was found at, zero length at the end of the source
+    pub span: Span,
+    // extra context, like what the user probably meant
+    pub note: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: &str, span: Span) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.to_owned(),
+            span,
+            note: None,
+        }
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.note = Some(note.to_owned());
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[line {}:{}] {}: {}",
+            self.span.line, self.span.column, self.severity, self.message
+        )?;
+        if let Some(note) = &self.note {
+            write!(f, "\n  note: {}", note)?;
+        }
+        Ok(())
+    }
+}
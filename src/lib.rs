@@ -0,0 +1,21 @@
+// rlox as a library, so Rust programs can embed the interpreter.
+// One Vm keeps its globals between calls:
+//
+//   let mut vm = rlox::Vm::init_vm();
+//   vm.interpret("fun add(a, b) { return a + b; }")?;
+//   let sum = vm.call_function("add", &[Value::number(1.0), Value::number(2.0)])?;
+pub mod chunk;
+pub mod compiler;
+pub mod debug;
+pub mod diagnostic;
+pub mod object;
+pub mod render;
+pub mod scanner;
+pub mod serialize;
+pub mod table;
+pub mod value;
+pub mod verify;
+pub mod vm;
+
+pub use value::Value;
+pub use vm::{LoxError, RuntimeError, SharedBuffer, VM as Vm};
@@ -1,61 +1,113 @@
-// mod chunk;
-#[path = "scanner.rs"]
-pub mod scanner;
-// use crate::compiler::chunk::*;
-#[path = "compiler.rs"]
-pub mod compiler;
-
-#[path = "debug.rs"]
-pub mod debug;
-// use crate::compiler::;
-
-#[path = "chunk.rs"]
-pub mod chunk;
-
-use std::io::BufRead;
-use std::io::Write;
-mod vm;
-
-fn main() {
-    let mut args = std::env::args();
-
-    if args.len() == 1 {
-        repl();
-    } else if args.len() == 2 {
-        runfile(std::path::PathBuf::from(args.nth(1).unwrap()));
-    } else {
-        println!("Usage: cargo run [file_path]");
+use std::process::ExitCode;
+
+use rlox::vm;
+
+// exit codes from sysexits.h
+const EX_USAGE: u8 = 64;
+const EX_DATAERR: u8 = 65;
+const EX_SOFTWARE: u8 = 70;
+const EX_IOERR: u8 = 74;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    let code = match args.as_slice() {
+        [_] => repl(),
+        [_, "compile", input, "-o", output] => compile_file(input, output),
+        [_, "run", path] => run_compiled(path),
+        [_, path] => runfile(path),
+        _ => {
+            eprintln!("Usage: cargo run [file_path]");
+            eprintln!("       cargo run compile [file_path] -o [output_path]");
+            eprintln!("       cargo run run [compiled_path]");
+            EX_USAGE
+        }
+    };
+    ExitCode::from(code)
+}
+
+fn exit_code(err: &vm::LoxError) -> u8 {
+    match err {
+        vm::LoxError::Compile(_) => EX_DATAERR,
+        vm::LoxError::Runtime(_) => EX_SOFTWARE,
+        // a file that won't load is bad input, just like bad source
+        vm::LoxError::Load(_) => EX_DATAERR,
     }
 }
 
-fn repl() {
-    // the compiler is single pass, so init the parser here?
-    loop {
-        print!(">>> ");
-        std::io::stdout().flush().unwrap();
-        let line = std::io::stdin().lock().lines().next().unwrap().unwrap();
-
-        if line == "exit" {
-            break;
-        } else {
-            let _s = vm::interpret(&line);
+fn repl() -> u8 {
+    // one VM for the whole session, so globals survive from line to line
+    let mut vm = vm::VM::init_vm();
+    match vm.repl() {
+        Ok(()) => 0,
+        Err(err) => {
+            eprintln!("Could not read input: {}", err);
+            EX_IOERR
         }
     }
-    println!("Exited.");
 }
 
-fn runfile(file_path: std::path::PathBuf) {
-    let source = std::fs::read_to_string(file_path).expect("invalid file path.");
-    println!("{:?}", source);
-    let _result = vm::interpret(&source);
+fn read_source(path: &str) -> Result<String, u8> {
+    std::fs::read_to_string(path).map_err(|err| {
+        eprintln!("Could not read '{}': {}", path, err);
+        EX_IOERR
+    })
+}
+
+fn runfile(path: &str) -> u8 {
+    let source = match read_source(path) {
+        Ok(source) => source,
+        Err(code) => return code,
+    };
+    let mut vm = vm::VM::init_vm();
+    match vm.interpret(&source) {
+        Ok(()) => 0,
+        Err(err) => {
+            vm.report(&err, Some(&source));
+            exit_code(&err)
+        }
+    }
 }
 
-// let ret = chunk.add_constant(24.2);
-// chunk.write_chunk(ret, 123);
-// chunk.write_chunk(chunk::OpCode::OpReturn, 123);
-// let output = vm::interpret(&mut vm_instance, &chunk);
-// debug::disassemble_chunk(&chunk, "test chunk");
-// debug::debug_stack_trace(&vm_instance);
-// println!("Rust Debug mode");
+fn compile_file(input: &str, output: &str) -> u8 {
+    let source = match read_source(input) {
+        Ok(source) => source,
+        Err(code) => return code,
+    };
+    match vm::compile_to_bytes(&source) {
+        Ok(bytes) => match std::fs::write(output, bytes) {
+            Ok(()) => 0,
+            Err(err) => {
+                eprintln!("Could not write '{}': {}", output, err);
+                EX_IOERR
+            }
+        },
+        Err(diagnostics) => {
+            let err = vm::LoxError::Compile(diagnostics);
+            vm::VM::init_vm().report(&err, Some(&source));
+            exit_code(&err)
+        }
+    }
+}
 
-// println!("{:?}", chunk);
+fn run_compiled(path: &str) -> u8 {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("Could not read '{}': {}", path, err);
+            return EX_IOERR;
+        }
+    };
+    let mut vm = vm::VM::init_vm();
+    match vm.interpret_bytes(&bytes) {
+        Ok(()) => 0,
+        Err(err) => {
+            match &err {
+                vm::LoxError::Load(load) => eprintln!("Could not load '{}': {}", path, load),
+                _ => vm.report(&err, None),
+            }
+            exit_code(&err)
+        }
+    }
+}
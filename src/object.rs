@@ -0,0 +1,622 @@
+// Heap allocated objects. Values only hold a handle into the heap,
+// the heap itself is owned by the VM
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::chunk::{Chunk, OpCode};
+use crate::table::{hash_string, Entry, Table};
+use crate::value::Value;
+use crate::vm::{RuntimeError, VM};
+
+// index of an object in the heap, cheap to copy around like a pointer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(pub usize);
+
+#[derive(Debug, Clone)]
+pub enum Obj {
+    String(ObjString),
+    Function(ObjFunction),
+    Closure(ObjClosure),
+    Upvalue(ObjUpvalue),
+    Class(ObjClass),
+    Instance(ObjInstance),
+    BoundMethod(ObjBoundMethod),
+    Native(ObjNative),
+}
+
+impl Obj {
+    // roughly what the object costs, used to decide when to collect
+    fn size(&self) -> usize {
+        let owned = match self {
+            Obj::String(x) => x.chars.capacity(),
+            Obj::Function(x) => {
+                x.chunk.code.capacity() * std::mem::size_of::<OpCode>()
+                    + x.chunk.constants.values.capacity() * std::mem::size_of::<Value>()
+                    + x.chunk.lines.byte_size()
+            }
+            Obj::Closure(x) => x.upvalues.capacity() * std::mem::size_of::<ObjRef>(),
+            Obj::Class(x) => x.methods.capacity() * std::mem::size_of::<Entry>(),
+            Obj::Instance(x) => x.fields.capacity() * std::mem::size_of::<Entry>(),
+            Obj::Upvalue(_) | Obj::BoundMethod(_) | Obj::Native(_) => 0,
+        };
+        std::mem::size_of::<Obj>() + owned
+    }
+
+    fn kind_name(&self) -> &'static str {
+        match self {
+            Obj::String(_) => "string",
+            Obj::Function(_) => "function",
+            Obj::Closure(_) => "closure",
+            Obj::Upvalue(_) => "upvalue",
+            Obj::Class(_) => "class",
+            Obj::Instance(_) => "instance",
+            Obj::BoundMethod(_) => "bound method",
+            Obj::Native(_) => "native",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjString {
+    pub chars: String,
+    pub hash: u32,
+}
+
+// every function owns the bytecode of its body
+#[derive(Debug, Clone)]
+pub struct ObjFunction {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+    // the top level script has no name
+    pub name: Option<ObjRef>,
+}
+
+impl ObjFunction {
+    pub fn init_function(name: Option<ObjRef>) -> Self {
+        Self {
+            arity: 0,
+            upvalue_count: 0,
+            chunk: Chunk::init_chunk(),
+            name,
+        }
+    }
+}
+
+// the runtime form of a function, together with the variables it captured
+#[derive(Debug, Clone)]
+pub struct ObjClosure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+// a captured variable. It points into the VM stack while the variable is
+// still alive there, and holds the value itself once the variable is closed
+#[derive(Debug, Clone, Copy)]
+pub enum ObjUpvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjClass {
+    pub name: ObjRef,
+    // method name to closure
+    pub methods: Table,
+}
+
+#[derive(Debug, Clone)]
+pub struct ObjInstance {
+    pub class: ObjRef,
+    pub fields: Table,
+}
+
+// a method pulled off an instance, it remembers the instance to use as `this`
+#[derive(Debug, Clone, Copy)]
+pub struct ObjBoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// a function implemented by the host. The arguments are still on the VM
+// stack while it runs, so they can't be collected under it
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
+// natives that carry their own state between calls
+pub trait NativeFunction {
+    fn call(&mut self, vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError>;
+
+    // the Lox values the native holds on to, so the collector keeps them alive.
+    // they are read when a collection starts and when a call starts, a value
+    // the native picks up in the middle of a call isn't protected until the
+    // call returns. Keep it on the stack or in a global until then
+    fn trace(&self) -> Vec<Value> {
+        Vec::new()
+    }
+}
+
+impl NativeFunction for NativeFn {
+    fn call(&mut self, vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+        self(vm, args)
+    }
+}
+
+#[derive(Clone)]
+pub enum NativeKind {
+    // called straight away, it has no state to lock
+    Plain(NativeFn),
+    // shared so the VM can hold on to it while the native borrows the VM
+    Object(Rc<RefCell<dyn NativeFunction>>),
+}
+
+#[derive(Clone)]
+pub struct ObjNative {
+    pub name: ObjRef,
+    pub arity: usize,
+    pub function: NativeKind,
+}
+
+impl fmt::Debug for ObjNative {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ObjNative")
+            .field("name", &self.name)
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+// the heap grows by this factor after every collection
+const GC_HEAP_GROW_FACTOR: usize = 2;
+const GC_FIRST_THRESHOLD: usize = 1024 * 1024;
+
+// a slot in the heap, freed slots are reused by later allocations
+#[derive(Debug)]
+struct HeapEntry {
+    obj: Obj,
+    is_marked: bool,
+    // what the object was accounted as when it was allocated
+    size: usize,
+}
+
+pub struct Heap {
+    objects: Vec<Option<HeapEntry>>,
+    free_slots: Vec<usize>,
+    // marked objects whose references haven't been traced yet
+    gray_stack: Vec<ObjRef>,
+    // every string is interned, so equal strings share one handle.
+    // the table doesn't keep its strings alive, see remove_white_strings
+    pub strings: Table,
+    pub bytes_allocated: usize,
+    pub next_gc: usize,
+    // collect before every allocation, shakes out missing roots
+    pub stress_gc: bool,
+    // where the collector traces what it does, if anywhere
+    log: Option<Box<dyn Write>>,
+}
+
+impl fmt::Debug for Heap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Heap")
+            .field("objects", &self.objects)
+            .field("free_slots", &self.free_slots)
+            .field("gray_stack", &self.gray_stack)
+            .field("strings", &self.strings)
+            .field("bytes_allocated", &self.bytes_allocated)
+            .field("next_gc", &self.next_gc)
+            .field("stress_gc", &self.stress_gc)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Heap {
+    pub fn init_heap() -> Self {
+        Self {
+            objects: Vec::<Option<HeapEntry>>::with_capacity(8),
+            free_slots: Vec::<usize>::new(),
+            gray_stack: Vec::<ObjRef>::new(),
+            strings: Table::init_table(),
+            bytes_allocated: 0,
+            next_gc: GC_FIRST_THRESHOLD,
+            stress_gc: cfg!(feature = "stress_gc"),
+            log: if cfg!(feature = "log_gc") {
+                Some(Box::new(io::stderr()))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn set_log(&mut self, log: Box<dyn Write>) {
+        self.log = Some(log);
+    }
+
+    // the line is only built when there is a log to write it to
+    fn log(&mut self, line: impl FnOnce(&Heap) -> String) {
+        if self.log.is_none() {
+            return;
+        }
+        let line = line(self);
+        if let Some(log) = self.log.as_mut() {
+            let _ = writeln!(log, "{}", line);
+        }
+    }
+
+    // never collects by itself, the owner of the roots decides when it is
+    // safe to do so and checks should_collect before allocating
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = obj.size();
+        self.bytes_allocated += size;
+        let entry = Some(HeapEntry {
+            obj,
+            is_marked: false,
+            size,
+        });
+        let obj = match self.free_slots.pop() {
+            Some(slot) => {
+                self.objects[slot] = entry;
+                ObjRef(slot)
+            }
+            None => {
+                self.objects.push(entry);
+                ObjRef(self.objects.len() - 1)
+            }
+        };
+        self.log(|heap| {
+            let kind = heap.get(obj).kind_name();
+            format!("#{} allocate {} for {}", obj.0, size, kind)
+        });
+        obj
+    }
+
+    // objects whose tables grew in place report it here, so the growth
+    // counts towards the next collection like a new allocation would
+    pub fn resize(&mut self, obj: ObjRef) {
+        let entry = match &mut self.objects[obj.0] {
+            Some(entry) => entry,
+            None => unreachable!("use of a freed object"),
+        };
+        let size = entry.obj.size();
+        self.bytes_allocated = self.bytes_allocated - entry.size + size;
+        entry.size = size;
+    }
+
+    // memory the program holds outside the heap objects, like the globals table
+    pub fn grow(&mut self, bytes: usize) {
+        self.bytes_allocated += bytes;
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress_gc || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(obj);
+        }
+    }
+
+    pub fn mark_object(&mut self, obj: ObjRef) {
+        let entry = self.objects[obj.0]
+            .as_mut()
+            .expect("marking a freed object");
+        if entry.is_marked {
+            return;
+        }
+        entry.is_marked = true;
+        self.gray_stack.push(obj);
+        self.log(|heap| format!("#{} mark {}", obj.0, heap.display(Value::obj(obj))));
+    }
+
+    pub fn mark_table(&mut self, table: &Table) {
+        for entry in table.entries.iter() {
+            if let Some(key) = entry.key {
+                self.mark_object(key);
+                self.mark_value(entry.value);
+            }
+        }
+    }
+
+    // turns a gray object black by marking everything it refers to
+    fn blacken_object(&mut self, obj: ObjRef) {
+        self.log(|heap| format!("#{} blacken {}", obj.0, heap.display(Value::obj(obj))));
+        let references: Vec<Value> = match self.get(obj) {
+            Obj::String(_) => Vec::new(),
+            Obj::Function(function) => {
+                let mut references = function.chunk.constants.values.clone();
+                references.extend(function.name.map(Value::obj));
+                references
+            }
+            Obj::Closure(closure) => std::iter::once(closure.function)
+                .chain(closure.upvalues.iter().copied())
+                .map(Value::obj)
+                .collect(),
+            Obj::Upvalue(ObjUpvalue::Open(_)) => Vec::new(),
+            Obj::Upvalue(ObjUpvalue::Closed(value)) => vec![*value],
+            Obj::Class(class) => {
+                let mut references = table_references(&class.methods);
+                references.push(Value::obj(class.name));
+                references
+            }
+            Obj::Instance(instance) => {
+                let mut references = table_references(&instance.fields);
+                references.push(Value::obj(instance.class));
+                references
+            }
+            Obj::BoundMethod(bound) => vec![bound.receiver, Value::obj(bound.method)],
+            Obj::Native(native) => {
+                let mut references = match &native.function {
+                    NativeKind::Plain(_) => Vec::new(),
+                    // a running native is borrowed, the VM roots its values instead
+                    NativeKind::Object(x) => x.try_borrow().map_or(Vec::new(), |x| x.trace()),
+                };
+                references.push(Value::obj(native.name));
+                references
+            }
+        };
+        for value in references {
+            self.mark_value(value);
+        }
+    }
+
+    // the roots have to be marked before this is called
+    pub fn collect_garbage(&mut self) {
+        let before = self.bytes_allocated;
+        self.log(|_| "-- gc begin".to_owned());
+
+        while let Some(obj) = self.gray_stack.pop() {
+            self.blacken_object(obj);
+        }
+        self.remove_white_strings();
+        self.sweep();
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(GC_FIRST_THRESHOLD);
+
+        self.log(|heap| {
+            format!(
+                "-- gc end\n   collected {} bytes (from {} to {}) next at {}",
+                before - heap.bytes_allocated,
+                before,
+                heap.bytes_allocated,
+                heap.next_gc
+            )
+        });
+    }
+
+    // the intern table must not hand out strings that are about to be freed
+    fn remove_white_strings(&mut self) {
+        for index in 0..self.strings.entries.len() {
+            let entry = self.strings.entries[index];
+            if let Some(key) = entry.key {
+                if !self.objects[key.0].as_ref().unwrap().is_marked {
+                    self.strings.delete(key, entry.hash);
+                }
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        for slot in 0..self.objects.len() {
+            let entry = match self.objects[slot].as_mut() {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if entry.is_marked {
+                // white again for the next collection
+                entry.is_marked = false;
+                continue;
+            }
+            let entry = self.objects[slot].take().unwrap();
+            self.bytes_allocated -= entry.size;
+            self.free_slots.push(slot);
+            let kind = entry.obj.kind_name();
+            self.log(|_| format!("#{} free {}", slot, kind));
+        }
+    }
+
+    pub fn get(&self, obj: ObjRef) -> &Obj {
+        match &self.objects[obj.0] {
+            Some(entry) => &entry.obj,
+            None => unreachable!("use of a freed object"),
+        }
+    }
+
+    pub fn get_mut(&mut self, obj: ObjRef) -> &mut Obj {
+        match &mut self.objects[obj.0] {
+            Some(entry) => &mut entry.obj,
+            None => unreachable!("use of a freed object"),
+        }
+    }
+
+    // copies the characters out of the source, used for string literals
+    pub fn copy_string(&mut self, chars: &str) -> ObjRef {
+        let hash = hash_string(chars);
+        match self.find_interned(chars, hash) {
+            Some(interned) => interned,
+            None => self.allocate_string(chars.to_owned(), hash),
+        }
+    }
+
+    // takes ownership of an already built string, used for concatenation
+    pub fn take_string(&mut self, chars: String) -> ObjRef {
+        let hash = hash_string(&chars);
+        match self.find_interned(&chars, hash) {
+            Some(interned) => interned,
+            None => self.allocate_string(chars, hash),
+        }
+    }
+
+    // the interned string with these characters, without allocating one
+    pub fn find_string(&self, chars: &str) -> Option<ObjRef> {
+        self.find_interned(chars, hash_string(chars))
+    }
+
+    fn find_interned(&self, chars: &str, hash: u32) -> Option<ObjRef> {
+        let objects = &self.objects;
+        self.strings.find_string(hash, |key| match &objects[key.0] {
+            Some(HeapEntry {
+                obj: Obj::String(x),
+                ..
+            }) => x.chars == chars,
+            _ => false,
+        })
+    }
+
+    fn allocate_string(&mut self, chars: String, hash: u32) -> ObjRef {
+        let string = self.alloc(Obj::String(ObjString { chars, hash }));
+        self.strings.set(string, hash, Value::nil());
+        string
+    }
+
+    pub fn as_string(&self, obj: ObjRef) -> &ObjString {
+        match self.get(obj) {
+            Obj::String(x) => x,
+            _ => unreachable!("object is not a string"),
+        }
+    }
+
+    pub fn as_function(&self, obj: ObjRef) -> &ObjFunction {
+        match self.get(obj) {
+            Obj::Function(x) => x,
+            _ => unreachable!("object is not a function"),
+        }
+    }
+
+    pub fn as_closure(&self, obj: ObjRef) -> &ObjClosure {
+        match self.get(obj) {
+            Obj::Closure(x) => x,
+            _ => unreachable!("object is not a closure"),
+        }
+    }
+
+    pub fn as_upvalue(&self, obj: ObjRef) -> &ObjUpvalue {
+        match self.get(obj) {
+            Obj::Upvalue(x) => x,
+            _ => unreachable!("object is not an upvalue"),
+        }
+    }
+
+    pub fn as_upvalue_mut(&mut self, obj: ObjRef) -> &mut ObjUpvalue {
+        match self.get_mut(obj) {
+            Obj::Upvalue(x) => x,
+            _ => unreachable!("object is not an upvalue"),
+        }
+    }
+
+    pub fn as_class(&self, obj: ObjRef) -> &ObjClass {
+        match self.get(obj) {
+            Obj::Class(x) => x,
+            _ => unreachable!("object is not a class"),
+        }
+    }
+
+    pub fn as_class_mut(&mut self, obj: ObjRef) -> &mut ObjClass {
+        match self.get_mut(obj) {
+            Obj::Class(x) => x,
+            _ => unreachable!("object is not a class"),
+        }
+    }
+
+    pub fn as_instance(&self, obj: ObjRef) -> &ObjInstance {
+        match self.get(obj) {
+            Obj::Instance(x) => x,
+            _ => unreachable!("object is not an instance"),
+        }
+    }
+
+    pub fn as_instance_mut(&mut self, obj: ObjRef) -> &mut ObjInstance {
+        match self.get_mut(obj) {
+            Obj::Instance(x) => x,
+            _ => unreachable!("object is not an instance"),
+        }
+    }
+
+    pub fn is_instance(&self, value: Value) -> bool {
+        match value {
+            Value::Obj(x) => matches!(self.get(x), Obj::Instance(_)),
+            _ => false,
+        }
+    }
+
+    pub fn is_class(&self, value: Value) -> bool {
+        match value {
+            Value::Obj(x) => matches!(self.get(x), Obj::Class(_)),
+            _ => false,
+        }
+    }
+
+    pub fn is_closure(&self, value: Value) -> bool {
+        match value {
+            Value::Obj(x) => matches!(self.get(x), Obj::Closure(_)),
+            _ => false,
+        }
+    }
+
+    pub fn is_string(&self, value: Value) -> bool {
+        match value {
+            Value::Obj(x) => matches!(self.get(x), Obj::String(_)),
+            _ => false,
+        }
+    }
+
+    pub fn display(&self, value: Value) -> DisplayValue<'_> {
+        DisplayValue { heap: self, value }
+    }
+}
+
+fn table_references(table: &Table) -> Vec<Value> {
+    let mut references = Vec::<Value>::with_capacity(table.count * 2);
+    for entry in table.entries.iter() {
+        if let Some(key) = entry.key {
+            references.push(Value::obj(key));
+            references.push(entry.value);
+        }
+    }
+    references
+}
+
+// Display needs to look into the heap to print objects
+pub struct DisplayValue<'a> {
+    heap: &'a Heap,
+    value: Value,
+}
+
+impl DisplayValue<'_> {
+    fn fmt_function(&self, f: &mut fmt::Formatter, function: &ObjFunction) -> fmt::Result {
+        match function.name {
+            Some(name) => write!(f, "<fn {}>", self.heap.as_string(name).chars),
+            None => write!(f, "<script>"),
+        }
+    }
+}
+
+impl fmt::Display for DisplayValue<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            Value::Obj(x) => match self.heap.get(x) {
+                Obj::String(s) => write!(f, "{}", s.chars),
+                Obj::Function(function) => self.fmt_function(f, function),
+                Obj::Closure(closure) => {
+                    self.fmt_function(f, self.heap.as_function(closure.function))
+                }
+                Obj::Upvalue(_) => write!(f, "upvalue"),
+                Obj::Class(class) => write!(f, "{}", self.heap.as_string(class.name).chars),
+                Obj::Instance(instance) => write!(
+                    f,
+                    "{} instance",
+                    self.heap
+                        .as_string(self.heap.as_class(instance.class).name)
+                        .chars
+                ),
+                Obj::BoundMethod(bound) => {
+                    let closure = self.heap.as_closure(bound.method);
+                    self.fmt_function(f, self.heap.as_function(closure.function))
+                }
+                Obj::Native(native) => {
+                    write!(f, "<native fn {}>", self.heap.as_string(native.name).chars)
+                }
+            },
+            value => write!(f, "{}", value),
+        }
+    }
+}
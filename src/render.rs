@@ -0,0 +1,149 @@
+// Pretty printing of diagnostics and runtime errors for the terminal: the
+// offending source line under a line number gutter, with the span underlined
+//
+//   error: Expect ';' after value.
+//    --> line 3:9
+//     |
+//   3 | print 1 +;
+//     |          ^
+use std::io::IsTerminal;
+
+use crate::chunk::Span;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::vm::{LoxError, RuntimeError};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+// tabs are expanded so the underline stays aligned with the text above it
+const TAB_WIDTH: usize = 4;
+
+pub struct Renderer<'a> {
+    // without the source only the location is printed
+    source: Option<&'a str>,
+    color: bool,
+}
+
+// honours https://no-color.org, and never colors output that isn't a terminal
+pub fn stderr_supports_color() -> bool {
+    std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal()
+}
+
+impl<'a> Renderer<'a> {
+    pub fn init_renderer(source: Option<&'a str>, color: bool) -> Self {
+        Self { source, color }
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_owned()
+        }
+    }
+
+    pub fn error(&self, err: &LoxError) -> String {
+        match err {
+            // a blank line after each diagnostic keeps them apart
+            LoxError::Compile(diagnostics) => diagnostics
+                .iter()
+                .map(|x| self.diagnostic(x) + "\n")
+                .collect::<Vec<String>>()
+                .join("\n"),
+            LoxError::Runtime(err) => self.runtime_error(err),
+            LoxError::Load(_) => err.to_string(),
+        }
+    }
+
+    pub fn diagnostic(&self, diagnostic: &Diagnostic) -> String {
+        let (label, style) = match diagnostic.severity {
+            Severity::Error => ("error", RED),
+            Severity::Warning => ("warning", YELLOW),
+        };
+        let mut out = self.header(label, style, &diagnostic.message, diagnostic.span);
+        if let Some(note) = &diagnostic.note {
+            out.push_str(&format!("\n{} note: {}", self.paint(BLUE, "  ="), note));
+        }
+        out
+    }
+
+    pub fn runtime_error(&self, err: &RuntimeError) -> String {
+        let span = Span {
+            line: err.line,
+            column: err.column,
+            length: err.length,
+        };
+        let mut out = self.header("runtime error", RED, &err.message, span);
+        for frame in err.trace.iter() {
+            out.push_str(&format!("\n{} {}", self.paint(BLUE, "  ="), frame));
+        }
+        out
+    }
+
+    // the label and message, the location, and the source snippet if there is one
+    fn header(&self, label: &str, style: &str, message: &str, span: Span) -> String {
+        let mut out = format!(
+            "{}{}",
+            self.paint(style, label),
+            self.paint(BOLD, &format!(": {}", message))
+        );
+        let snippet = self
+            .source
+            .and_then(|x| x.lines().nth((span.line as usize).checked_sub(1)?));
+        let gutter = " ".repeat(span.line.to_string().len());
+        out.push_str(&format!(
+            "\n{}{} line {}:{}",
+            gutter,
+            self.paint(BLUE, "-->"),
+            span.line,
+            span.column
+        ));
+        if let Some(text) = snippet {
+            let bar = self.paint(BLUE, "|");
+            let (text, padding, width) = underline(text, span);
+            out.push_str(&format!("\n{} {}", gutter, bar));
+            out.push_str(&format!(
+                "\n{} {} {}",
+                self.paint(BLUE, &span.line.to_string()),
+                bar,
+                text
+            ));
+            out.push_str(&format!(
+                "\n{} {} {}{}",
+                gutter,
+                bar,
+                " ".repeat(padding),
+                self.paint(style, &"^".repeat(width))
+            ));
+        }
+        out
+    }
+}
+
+// the line with tabs expanded, how far the underline is indented, and how
+// wide it is. spans running past the end of the line are cut off there
+fn underline(line: &str, span: Span) -> (String, usize, usize) {
+    let mut text = String::with_capacity(line.len());
+    let mut padding = 0;
+    let mut width = 0;
+    let mut span_bytes = 0;
+    for (index, c) in line.chars().enumerate() {
+        let shown = if c == '\t' { TAB_WIDTH } else { 1 };
+        if c == '\t' {
+            text.push_str(&" ".repeat(TAB_WIDTH));
+        } else {
+            text.push(c);
+        }
+        if index + 1 < span.column {
+            padding += shown;
+        } else if span_bytes < span.length {
+            width += shown;
+            span_bytes += c.len_utf8();
+        }
+    }
+    // zero length spans, like the end of the source, still get a caret
+    (text, padding, width.max(1))
+}
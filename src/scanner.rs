@@ -52,6 +52,8 @@ pub struct Token {
     pub length: usize,
     pub start: usize,
     pub line: i32,
+    // what went wrong, only set on error tokens
+    pub message: Option<&'static str>,
 }
 
 #[derive(Debug, Clone)]
@@ -70,10 +72,11 @@ impl Scanner {
         }
     }
 
-    pub fn advance<'a>(&mut self, source: &'a str) -> &'a str {
-        self.current += 1;
-        // println!("{}", self.current);
-        source.get(self.current - 1..self.current).unwrap()
+    // consumes a whole character, so tokens never split one
+    pub fn advance(&mut self, source: &str) -> char {
+        let c = source[self.current..].chars().next().unwrap();
+        self.current += c.len_utf8();
+        c
     }
 
     fn match_with(&mut self, source: &str, expected: char, length: usize) -> bool {
@@ -103,45 +106,31 @@ impl Scanner {
                     self.line += 1;
                     self.current += 1;
                 }
-                '#' => {
-                    while self.current < source.len() && self.peek(source).unwrap() != '\n' {
-                        self.current += 1;
-                    }
-                }
+                '#' => self.skip_comment(source),
+                // `//` comments, as in clox and the Crafting Interpreters tests
+                '/' if self.peek_next(source) == Some('/') => self.skip_comment(source),
                 _ => return,
             }
         }
     }
 
-    fn peek(&mut self, source: &str) -> Option<char> {
-        if source.get(self.current..=self.current).is_none() {
-            None
-        } else {
-            Some(
-                source
-                    .get(self.current..=self.current)
-                    .unwrap()
-                    .chars()
-                    .nth(0)
-                    .unwrap(),
-            )
+    // a comment runs until the end of the line
+    fn skip_comment(&mut self, source: &str) {
+        // bytewise, comments may hold characters longer than one byte
+        while self.current < source.len() && source.as_bytes()[self.current] != b'\n' {
+            self.current += 1;
         }
     }
 
+    // the syntax is all ascii, so looking at single bytes is enough. A byte of
+    // a longer character never matches anything, and only strings and
+    // comments step over them byte by byte
+    fn peek(&mut self, source: &str) -> Option<char> {
+        source.as_bytes().get(self.current).map(|x| *x as char)
+    }
+
     fn peek_next(&mut self, source: &str) -> Option<char> {
-        if source.get(self.current + 1..=self.current + 1).is_none() {
-            println!("cursor at {}", self.current);
-            None
-        } else {
-            Some(
-                source
-                    .get(self.current + 1..=self.current + 1)
-                    .unwrap()
-                    .chars()
-                    .nth(0)
-                    .unwrap(),
-            )
-        }
+        source.as_bytes().get(self.current + 1).map(|x| *x as char)
     }
 
     fn make_token(&mut self, kind: TokenKind) -> Token {
@@ -150,29 +139,25 @@ impl Scanner {
             start: self.start,
             length: self.current - self.start,
             line: self.line,
+            message: None,
         }
     }
 
-    pub fn error_token(&mut self, message: &str) -> Token {
-        eprintln!(
-            "{}",
-            format!(
-                "Error encountered at {}, col{} to {}",
-                self.line, self.start, self.current
-            )
-        );
+    // the token still covers the offending characters, so the error can point at them
+    pub fn error_token(&mut self, message: &'static str) -> Token {
         Token {
             kind: TokenKind::TokenError,
-            start: 0,
-            length: message.len(),
+            start: self.start,
+            length: self.current - self.start,
             line: self.line,
+            message: Some(message),
         }
     }
 
     fn char_at_start(&self, source: &str) -> Option<char> {
         match source.get(self.start + 1..=self.start + 1) {
-            Some(x) => return x.chars().nth(0),
-            None => return None,
+            Some(x) => x.chars().nth(0),
+            None => None,
         }
     }
 
@@ -182,108 +167,65 @@ impl Scanner {
             Some(x) => {
                 let y = x.chars().nth(0).unwrap();
                 match y {
-                    'a' => return self.check_keyword(1, 2, "nd", TokenKind::TokenAnd, source),
-                    'c' => return self.check_keyword(1, 4, "lass", TokenKind::TokenClass, source),
-                    'e' => return self.check_keyword(1, 3, "lse", TokenKind::TokenElse, source),
-                    'i' => return self.check_keyword(1, 1, "f", TokenKind::TokenIf, source),
-                    'n' => return self.check_keyword(1, 2, "il", TokenKind::TokenNil, source),
-                    'o' => return self.check_keyword(1, 1, "r", TokenKind::TokenOr, source),
-                    'p' => return self.check_keyword(1, 4, "rint", TokenKind::TokenPrint, source),
-                    'r' => {
-                        return self.check_keyword(1, 5, "eturn", TokenKind::TokenReturn, source)
-                    }
-                    's' => return self.check_keyword(1, 4, "uper", TokenKind::TokenSuper, source),
-                    'w' => return self.check_keyword(1, 4, "hile", TokenKind::TokenWhile, source),
+                    'a' => self.check_keyword(1, 2, "nd", TokenKind::TokenAnd, source),
+                    'c' => self.check_keyword(1, 4, "lass", TokenKind::TokenClass, source),
+                    'e' => self.check_keyword(1, 3, "lse", TokenKind::TokenElse, source),
+                    'i' => self.check_keyword(1, 1, "f", TokenKind::TokenIf, source),
+                    'n' => self.check_keyword(1, 2, "il", TokenKind::TokenNil, source),
+                    'o' => self.check_keyword(1, 1, "r", TokenKind::TokenOr, source),
+                    'p' => self.check_keyword(1, 4, "rint", TokenKind::TokenPrint, source),
+                    'r' => self.check_keyword(1, 5, "eturn", TokenKind::TokenReturn, source),
+                    's' => self.check_keyword(1, 4, "uper", TokenKind::TokenSuper, source),
+                    'v' => self.check_keyword(1, 2, "ar", TokenKind::TokenVar, source),
+                    'w' => self.check_keyword(1, 4, "hile", TokenKind::TokenWhile, source),
                     // trie now branches
                     'f' => {
                         if self.current - self.start > 1 {
                             match self.char_at_start(source) {
-                                Some(x) => {
-                                    match x {
-                                        'o' => {
-                                            return self.check_keyword(
-                                                2,
-                                                1,
-                                                "r",
-                                                TokenKind::TokenFor,
-                                                source,
-                                            )
-                                        }
-                                        'u' => {
-                                            return self.check_keyword(
-                                                2,
-                                                1,
-                                                "n",
-                                                TokenKind::TokenFun,
-                                                source,
-                                            )
-                                        }
-                                        'a' => {
-                                            return self.check_keyword(
-                                                2,
-                                                3,
-                                                "lse",
-                                                TokenKind::TokenFalse,
-                                                source,
-                                            )
-                                        }
-                                        _ => {
-                                            println!("No match for f");
-                                            return TokenKind::TokenIdentifier;
-                                        }
-                                    };
-                                }
-                                None => {
-                                    return TokenKind::TokenError;
-                                }
+                                Some(x) => match x {
+                                    'o' => {
+                                        self.check_keyword(2, 1, "r", TokenKind::TokenFor, source)
+                                    }
+                                    'u' => {
+                                        self.check_keyword(2, 1, "n", TokenKind::TokenFun, source)
+                                    }
+                                    'a' => self.check_keyword(
+                                        2,
+                                        3,
+                                        "lse",
+                                        TokenKind::TokenFalse,
+                                        source,
+                                    ),
+                                    _ => TokenKind::TokenIdentifier,
+                                },
+                                None => TokenKind::TokenError,
                             }
                         } else {
-                            return TokenKind::TokenIdentifier;
+                            TokenKind::TokenIdentifier
                         }
                     }
                     't' => {
                         if self.current - self.start > 1 {
                             match self.char_at_start(source) {
-                                Some(x) => {
-                                    match x {
-                                        'h' => {
-                                            return self.check_keyword(
-                                                2,
-                                                2,
-                                                "is",
-                                                TokenKind::TokenThis,
-                                                source,
-                                            )
-                                        }
-                                        'r' => {
-                                            return self.check_keyword(
-                                                2,
-                                                2,
-                                                "ue",
-                                                TokenKind::TokenTrue,
-                                                source,
-                                            )
-                                        }
-                                        _ => return TokenKind::TokenIdentifier,
-                                    };
-                                }
-                                None => {
-                                    return TokenKind::TokenError;
-                                }
+                                Some(x) => match x {
+                                    'h' => {
+                                        self.check_keyword(2, 2, "is", TokenKind::TokenThis, source)
+                                    }
+                                    'r' => {
+                                        self.check_keyword(2, 2, "ue", TokenKind::TokenTrue, source)
+                                    }
+                                    _ => TokenKind::TokenIdentifier,
+                                },
+                                None => TokenKind::TokenError,
                             }
                         } else {
-                            return TokenKind::TokenIdentifier;
+                            TokenKind::TokenIdentifier
                         }
                     }
-                    _ => {
-                        // println!("{}", y)
-                        return TokenKind::TokenIdentifier;
-                    }
+                    _ => TokenKind::TokenIdentifier,
                 }
             }
-            None => {
-                return TokenKind::TokenError;
-            }
+            None => TokenKind::TokenError,
         }
         // TokenKind::TokenIdentifier
     }
@@ -314,7 +256,7 @@ impl Scanner {
 
         if self.peek(source).is_some()
             && self.peek(source).unwrap() == '.'
-            && self.peek_next(source).unwrap().is_ascii_digit()
+            && self.peek_next(source).is_some_and(|x| x.is_ascii_digit())
         {
             self.current += 1;
             while self.peek(source).is_some() && self.peek(source).unwrap().is_ascii_digit() {
@@ -327,9 +269,9 @@ impl Scanner {
             && (self.peek(source).unwrap().is_ascii_alphabetic()
                 || self.peek(source).unwrap() == '_')
         {
-            return self.error_token("Error: cannot start an identifier with a number!");
+            return self.error_token("Identifiers can't start with a digit.");
         }
-        return self.make_token(TokenKind::TokenNumber);
+        self.make_token(TokenKind::TokenNumber)
     }
 
     fn identifier(&mut self, source: &str) -> Token {
@@ -346,8 +288,7 @@ impl Scanner {
 
     fn string(&mut self, source: &str) -> Token {
         //consume chars till another '"' is encountered, take care of newlines
-        let poss_line = self.line;
-        let poss_col = self.current;
+        let start_line = self.line;
         while self.peek(source).is_some() && self.peek(source).unwrap() != '"' {
             if self.peek(source).unwrap() == '\n' {
                 self.line += 1;
@@ -356,8 +297,10 @@ impl Scanner {
         }
 
         if self.peek(source).is_none() {
-            println!("Unterminated String {} col {} error:", poss_line, poss_col);
-            return self.error_token("Unterminated String encountered.");
+            // report it where the string starts, not at the end of the file
+            let mut token = self.error_token("Unterminated string.");
+            token.line = start_line;
+            return token;
         }
 
         //consume the closing quote!
@@ -369,13 +312,10 @@ impl Scanner {
         self.skip_whitespaces(source);
         self.start = self.current;
 
-        // println!("Source length {length}");
-        // println!("Scanner at {}", scanner.current);
-
         if self.current == length {
             return self.make_token(TokenKind::TokenEof);
         }
-        let c = self.advance(source).chars().nth(0).unwrap();
+        let c = self.advance(source);
 
         // match for identifiers
         if c.is_ascii_alphabetic() || c == '_' {
@@ -385,49 +325,48 @@ impl Scanner {
             return self.number(source);
         }
 
-        // println!("Matched char {}", c);
         match c {
-            '(' => return self.make_token(TokenKind::TokenLeftParen),
-            ')' => return self.make_token(TokenKind::TokenRightParen),
-            '{' => return self.make_token(TokenKind::TokenLeftBrace),
-            '}' => return self.make_token(TokenKind::TokenRightBrace),
-            ';' => return self.make_token(TokenKind::TokenSemiColon),
-            ',' => return self.make_token(TokenKind::TokenComma),
-            '.' => return self.make_token(TokenKind::TokenPeriod),
-            '-' => return self.make_token(TokenKind::TokenMinus),
-            '+' => return self.make_token(TokenKind::TokenPlus),
-            '/' => return self.make_token(TokenKind::TokenSlash),
-            '*' => return self.make_token(TokenKind::TokenStar),
+            '(' => self.make_token(TokenKind::TokenLeftParen),
+            ')' => self.make_token(TokenKind::TokenRightParen),
+            '{' => self.make_token(TokenKind::TokenLeftBrace),
+            '}' => self.make_token(TokenKind::TokenRightBrace),
+            ';' => self.make_token(TokenKind::TokenSemiColon),
+            ',' => self.make_token(TokenKind::TokenComma),
+            '.' => self.make_token(TokenKind::TokenPeriod),
+            '-' => self.make_token(TokenKind::TokenMinus),
+            '+' => self.make_token(TokenKind::TokenPlus),
+            '/' => self.make_token(TokenKind::TokenSlash),
+            '*' => self.make_token(TokenKind::TokenStar),
             '!' => {
                 if self.match_with(source, '=', length) {
-                    return self.make_token(TokenKind::TokenBangEqual);
+                    self.make_token(TokenKind::TokenBangEqual)
                 } else {
-                    return self.make_token(TokenKind::TokenBang);
+                    self.make_token(TokenKind::TokenBang)
                 }
             }
             '=' => {
                 if self.match_with(source, '=', length) {
-                    return self.make_token(TokenKind::TokenEqualEqual);
+                    self.make_token(TokenKind::TokenEqualEqual)
                 } else {
-                    return self.make_token(TokenKind::TokenEqual);
+                    self.make_token(TokenKind::TokenEqual)
                 }
             }
             '<' => {
                 if self.match_with(source, '=', length) {
-                    return self.make_token(TokenKind::TokenLessEqual);
+                    self.make_token(TokenKind::TokenLessEqual)
                 } else {
-                    return self.make_token(TokenKind::TokenLess);
+                    self.make_token(TokenKind::TokenLess)
                 }
             }
             '>' => {
                 if self.match_with(source, '=', length) {
-                    return self.make_token(TokenKind::TokenGreaterEqual);
+                    self.make_token(TokenKind::TokenGreaterEqual)
                 } else {
-                    return self.make_token(TokenKind::TokenGreater);
+                    self.make_token(TokenKind::TokenGreater)
                 }
             }
             '"' => self.string(source),
-            _ => self.error_token("Unexpected character encountered."),
+            _ => self.error_token("Unexpected character."),
         }
     }
 }
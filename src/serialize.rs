@@ -0,0 +1,233 @@
+// Compiled scripts on disk. A file holds the top level function, every
+// function declared in it is nested inside its parent's constant pool.
+//
+// layout, all integers little endian:
+//   magic "LOXC", format version u16, then the script function:
+//   function = name (u8 flag, then a string if set), arity u32, upvalue count u32,
+//              code (u32 length, bytes), line table (u32 length, the encoded runs),
+//              constants (u32 count, then a tag byte and the payload of each)
+//   string   = u32 length, utf-8 bytes
+use std::fmt;
+
+use crate::chunk::{Chunk, LineTable};
+use crate::object::{Heap, Obj, ObjFunction, ObjRef};
+use crate::value::Value;
+use crate::verify::VerifyError;
+
+const MAGIC: &[u8; 4] = b"LOXC";
+// bump whenever the layout or the meaning of an opcode changes
+pub const FORMAT_VERSION: u16 = 1;
+
+// how deep function constants may nest. The loader recurses into nested
+// functions, so a file can't be allowed to go arbitrarily deep
+const MAX_NESTING: usize = 256;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_FUNCTION: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    NotBytecode,
+    VersionMismatch { found: u16 },
+    Truncated,
+    Malformed(&'static str),
+    // well formed, but the code itself is unsafe to run
+    Unverified(VerifyError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "not a compiled Lox file"),
+            LoadError::VersionMismatch { found } => write!(
+                f,
+                "compiled with bytecode version {}, this rlox reads version {}",
+                found, FORMAT_VERSION
+            ),
+            LoadError::Truncated => write!(f, "file is truncated"),
+            LoadError::Malformed(message) => write!(f, "malformed file: {}", message),
+            LoadError::Unverified(err) => write!(f, "rejected by the verifier: {}", err),
+        }
+    }
+}
+
+pub fn write_script(heap: &Heap, function: ObjRef) -> Vec<u8> {
+    let mut out = Vec::<u8>::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    write_function(&mut out, heap, heap.as_function(function));
+    out
+}
+
+fn write_u32(out: &mut Vec<u8>, value: usize) {
+    out.extend_from_slice(&(value as u32).to_le_bytes());
+}
+
+fn write_string(out: &mut Vec<u8>, chars: &str) {
+    write_u32(out, chars.len());
+    out.extend_from_slice(chars.as_bytes());
+}
+
+fn write_function(out: &mut Vec<u8>, heap: &Heap, function: &ObjFunction) {
+    match function.name {
+        Some(name) => {
+            out.push(1);
+            write_string(out, &heap.as_string(name).chars);
+        }
+        None => out.push(0),
+    }
+    write_u32(out, function.arity);
+    write_u32(out, function.upvalue_count);
+
+    let chunk = &function.chunk;
+    write_u32(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+
+    let lines = chunk.lines.as_bytes();
+    write_u32(out, lines.len());
+    out.extend_from_slice(lines);
+
+    write_u32(out, chunk.constants.values.len());
+    for constant in chunk.constants.values.iter() {
+        match *constant {
+            Value::Nil => out.push(TAG_NIL),
+            Value::Bool(x) => {
+                out.push(TAG_BOOL);
+                out.push(x as u8);
+            }
+            Value::Number(x) => {
+                out.push(TAG_NUMBER);
+                out.extend_from_slice(&x.to_le_bytes());
+            }
+            Value::Obj(x) => match heap.get(x) {
+                Obj::String(string) => {
+                    out.push(TAG_STRING);
+                    write_string(out, &string.chars);
+                }
+                Obj::Function(nested) => {
+                    out.push(TAG_FUNCTION);
+                    write_function(out, heap, nested);
+                }
+                _ => unreachable!("the compiler only emits string and function objects"),
+            },
+        }
+    }
+}
+
+// loads the script function into the heap. The loader doesn't collect, the
+// caller has to root the function before allocating anything else
+pub fn read_script(bytes: &[u8], heap: &mut Heap) -> Result<ObjRef, LoadError> {
+    let mut reader = Reader {
+        bytes,
+        position: 0,
+        depth: 0,
+    };
+    if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err(LoadError::NotBytecode);
+    }
+    let version = u16::from_le_bytes(reader.array()?);
+    if version != FORMAT_VERSION {
+        return Err(LoadError::VersionMismatch { found: version });
+    }
+    let function = reader.function(heap)?;
+    if reader.position != bytes.len() {
+        return Err(LoadError::Malformed("trailing bytes after the script"));
+    }
+    Ok(heap.alloc(Obj::Function(function)))
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    // how many functions the one being read is nested in
+    depth: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], LoadError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .ok_or(LoadError::Truncated)?;
+        let bytes = self
+            .bytes
+            .get(self.position..end)
+            .ok_or(LoadError::Truncated)?;
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u8(&mut self) -> Result<u8, LoadError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    // a count of items that are at least min_size bytes each, checked against
+    // what is left so a corrupt count can't ask for a huge allocation
+    fn count(&mut self, min_size: usize) -> Result<usize, LoadError> {
+        let count = self.u32()? as usize;
+        if count.saturating_mul(min_size) > self.bytes.len() - self.position {
+            return Err(LoadError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn string(&mut self, heap: &mut Heap) -> Result<ObjRef, LoadError> {
+        let length = self.count(1)?;
+        let chars = std::str::from_utf8(self.take(length)?)
+            .map_err(|_| LoadError::Malformed("string is not valid utf-8"))?;
+        Ok(heap.copy_string(chars))
+    }
+
+    fn function(&mut self, heap: &mut Heap) -> Result<ObjFunction, LoadError> {
+        let name = match self.u8()? {
+            0 => None,
+            1 => Some(self.string(heap)?),
+            _ => return Err(LoadError::Malformed("bad function name flag")),
+        };
+        let mut function = ObjFunction::init_function(name);
+        function.arity = self.u32()? as usize;
+        function.upvalue_count = self.u32()? as usize;
+
+        let mut chunk = Chunk::init_chunk();
+        let code_length = self.count(1)?;
+        chunk.code = self.take(code_length)?.to_vec();
+        chunk.count = chunk.code.len();
+
+        let lines_length = self.count(1)?;
+        chunk.lines = LineTable::from_bytes(self.take(lines_length)?)
+            .ok_or(LoadError::Malformed("bad line table"))?;
+
+        for _ in 0..self.count(1)? {
+            let constant = match self.u8()? {
+                TAG_NIL => Value::nil(),
+                TAG_BOOL => Value::bool(self.u8()? != 0),
+                TAG_NUMBER => Value::number(f64::from_le_bytes(self.array()?)),
+                TAG_STRING => Value::obj(self.string(heap)?),
+                TAG_FUNCTION => {
+                    if self.depth == MAX_NESTING {
+                        return Err(LoadError::Malformed("functions are nested too deeply"));
+                    }
+                    self.depth += 1;
+                    let nested = self.function(heap)?;
+                    self.depth -= 1;
+                    Value::obj(heap.alloc(Obj::Function(nested)))
+                }
+                _ => return Err(LoadError::Malformed("unknown constant type")),
+            };
+            chunk.constants.write_value_array(constant);
+        }
+        function.chunk = chunk;
+        Ok(function)
+    }
+}
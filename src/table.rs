@@ -0,0 +1,183 @@
+// Open addressing hash table keyed by interned strings, like clox's Table.
+// The keys are handles into the heap, so the hash of each key is cached in the entry
+use crate::object::ObjRef;
+use crate::value::Value;
+
+const TABLE_MAX_LOAD: f64 = 0.75;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Entry {
+    pub key: Option<ObjRef>,
+    pub hash: u32,
+    pub value: Value,
+}
+
+impl Entry {
+    fn empty() -> Self {
+        Self {
+            key: None,
+            hash: 0,
+            value: Value::nil(),
+        }
+    }
+
+    // a deleted entry keeps probing sequences alive, it is marked with a true value
+    fn is_tombstone(&self) -> bool {
+        self.key.is_none() && !self.value.is_nil()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Table {
+    // live entries plus tombstones, so the load factor accounts for both
+    pub count: usize,
+    pub entries: Vec<Entry>,
+}
+
+// FNV-1a, the same hash clox uses for its strings
+pub fn hash_string(chars: &str) -> u32 {
+    let mut hash: u32 = 2166136261;
+    for byte in chars.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(16777619);
+    }
+    hash
+}
+
+impl Table {
+    pub fn init_table() -> Self {
+        Self {
+            count: 0,
+            entries: Vec::<Entry>::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn load_factor(&self) -> f64 {
+        if self.entries.is_empty() {
+            0f64
+        } else {
+            self.count as f64 / self.entries.len() as f64
+        }
+    }
+
+    // index of the entry for key, or of the slot it should be inserted into
+    fn find_entry(entries: &[Entry], key: ObjRef, hash: u32) -> usize {
+        let capacity = entries.len();
+        let mut index = hash as usize % capacity;
+        let mut tombstone: Option<usize> = None;
+        loop {
+            let entry = &entries[index];
+            match entry.key {
+                None => {
+                    if !entry.is_tombstone() {
+                        return tombstone.unwrap_or(index);
+                    } else if tombstone.is_none() {
+                        tombstone = Some(index);
+                    }
+                }
+                Some(x) if x == key => return index,
+                Some(_) => {}
+            }
+            index = (index + 1) % capacity;
+        }
+    }
+
+    fn adjust_capacity(&mut self, capacity: usize) {
+        let mut entries = vec![Entry::empty(); capacity];
+        self.count = 0;
+        for entry in self.entries.iter() {
+            if let Some(key) = entry.key {
+                let index = Table::find_entry(&entries, key, entry.hash);
+                entries[index] = *entry;
+                self.count += 1;
+            }
+        }
+        self.entries = entries;
+    }
+
+    pub fn get(&self, key: ObjRef, hash: u32) -> Option<Value> {
+        if self.count == 0 {
+            return None;
+        }
+        let entry = &self.entries[Table::find_entry(&self.entries, key, hash)];
+        entry.key.map(|_| entry.value)
+    }
+
+    // returns true if the key was not in the table before
+    pub fn set(&mut self, key: ObjRef, hash: u32, value: Value) -> bool {
+        if (self.count + 1) as f64 > self.capacity() as f64 * TABLE_MAX_LOAD {
+            let capacity = if self.capacity() < 8 {
+                8
+            } else {
+                self.capacity() * 2
+            };
+            self.adjust_capacity(capacity);
+        }
+
+        let index = Table::find_entry(&self.entries, key, hash);
+        let entry = &mut self.entries[index];
+        let is_new_key = entry.key.is_none();
+        // reusing a tombstone does not change the count, it was already counted
+        if is_new_key && !entry.is_tombstone() {
+            self.count += 1;
+        }
+        *entry = Entry {
+            key: Some(key),
+            hash,
+            value,
+        };
+        is_new_key
+    }
+
+    pub fn delete(&mut self, key: ObjRef, hash: u32) -> bool {
+        if self.count == 0 {
+            return false;
+        }
+        let index = Table::find_entry(&self.entries, key, hash);
+        let entry = &mut self.entries[index];
+        if entry.key.is_none() {
+            return false;
+        }
+        *entry = Entry {
+            key: None,
+            hash: 0,
+            value: Value::bool(true),
+        };
+        true
+    }
+
+    pub fn add_all(&self, to: &mut Table) {
+        for entry in self.entries.iter() {
+            if let Some(key) = entry.key {
+                to.set(key, entry.hash, entry.value);
+            }
+        }
+    }
+
+    // the one lookup that compares contents instead of handles, used for interning.
+    // is_match is asked whether the candidate key holds the characters we look for
+    pub fn find_string(&self, hash: u32, is_match: impl Fn(ObjRef) -> bool) -> Option<ObjRef> {
+        if self.count == 0 {
+            return None;
+        }
+        let capacity = self.entries.len();
+        let mut index = hash as usize % capacity;
+        loop {
+            let entry = &self.entries[index];
+            match entry.key {
+                None => {
+                    if !entry.is_tombstone() {
+                        return None;
+                    }
+                }
+                Some(key) if entry.hash == hash && is_match(key) => return Some(key),
+                Some(_) => {}
+            }
+            index = (index + 1) % capacity;
+        }
+    }
+}
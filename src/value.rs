@@ -1,12 +1,138 @@
 // Representing values in the VM requires a struct to hold them
 //
+use std::fmt;
 
-pub type Value = f64;
+use crate::object::ObjRef;
+
+// Every value carries its own type tag, so the VM can check operands at runtime
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    #[default]
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(ObjRef),
+}
+
+impl Value {
+    pub fn nil() -> Self {
+        Value::Nil
+    }
+
+    pub fn bool(value: bool) -> Self {
+        Value::Bool(value)
+    }
+
+    pub fn number(value: f64) -> Self {
+        Value::Number(value)
+    }
+
+    pub fn obj(obj: ObjRef) -> Self {
+        Value::Obj(obj)
+    }
+
+    pub fn is_nil(&self) -> bool {
+        matches!(self, Value::Nil)
+    }
+
+    pub fn is_bool(&self) -> bool {
+        matches!(self, Value::Bool(_))
+    }
+
+    pub fn is_number(&self) -> bool {
+        matches!(self, Value::Number(_))
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn is_obj(&self) -> bool {
+        matches!(self, Value::Obj(_))
+    }
+
+    // nil and false are falsey, every other value is truthy
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_obj(&self) -> Option<ObjRef> {
+        match self {
+            Value::Obj(x) => Some(*x),
+            _ => None,
+        }
+    }
+}
+
+// values of different types are never equal, there is no implicit conversion
+pub fn values_equal(a: Value, b: Value) -> bool {
+    match (a, b) {
+        (Value::Nil, Value::Nil) => true,
+        (Value::Bool(x), Value::Bool(y)) => x == y,
+        (Value::Number(x), Value::Number(y)) => x == y,
+        (Value::Obj(x), Value::Obj(y)) => x == y,
+        _ => false,
+    }
+}
+
+// prints numbers the way clox's printf("%g") does: six significant digits,
+// switching to an exponent for very large and very small numbers
+pub fn format_number(x: f64) -> String {
+    const PRECISION: i32 = 6;
+    if x.is_nan() {
+        return "nan".to_owned();
+    }
+    if x.is_infinite() {
+        return if x < 0.0 { "-inf" } else { "inf" }.to_owned();
+    }
+    // the exponent after rounding to six digits, 999999.5 is already 1e+06
+    let scientific = format!("{:.*e}", (PRECISION - 1) as usize, x);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if (-4..PRECISION).contains(&exponent) {
+        let decimals = (PRECISION - 1 - exponent) as usize;
+        trim_zeros(&format!("{:.*}", decimals, x)).to_owned()
+    } else {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim_zeros(mantissa), sign, exponent.abs())
+    }
+}
+
+// drops the zeros after the decimal point, and the point if nothing is left
+fn trim_zeros(digits: &str) -> &str {
+    if digits.contains('.') {
+        digits.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        digits
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(x) => write!(f, "{}", x),
+            Value::Number(x) => write!(f, "{}", format_number(*x)),
+            // the contents live in the heap, see Heap::display
+            Value::Obj(x) => write!(f, "<obj {}>", x.0),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct ValueArray {
     pub count: usize,
-    pub values: Vec<Value>, // all the numerical values are float under the hood
+    pub values: Vec<Value>,
 }
 
 impl ValueArray {
@@ -0,0 +1,321 @@
+// Checks bytecode from outside the compiler before the VM runs it. The VM
+// trusts the operands completely, a bad one would index out of bounds or load
+// a number where a name or a function belongs, so anything loaded from a file
+// goes through here. What ends up on the stack is only known at runtime, the
+// VM checks the types of those values itself.
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::chunk::{Instruction, OpCode};
+use crate::object::{Heap, Obj, ObjFunction, ObjRef};
+use crate::value::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VerifyError {
+    // the function the bad instruction is in, as it would be printed
+    pub function: String,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} at offset {}: {}",
+            self.function, self.offset, self.message
+        )
+    }
+}
+
+// verifies the script and every function nested in it
+pub fn verify_script(heap: &Heap, function: ObjRef) -> Result<(), VerifyError> {
+    // the script runs as a closure without any upvalues
+    let upvalue_count = heap.as_function(function).upvalue_count;
+    if upvalue_count != 0 {
+        let message = format!("the script has {} upvalues", upvalue_count);
+        return Err(VerifyError {
+            function: "<script>".to_owned(),
+            offset: 0,
+            message,
+        });
+    }
+    // many closures can share a function, each one is only verified once
+    let mut verified = HashSet::<ObjRef>::new();
+    let mut pending = vec![function];
+    while let Some(function) = pending.pop() {
+        if verified.insert(function) {
+            pending.extend(verify_function(heap, function)?);
+        }
+    }
+    Ok(())
+}
+
+struct Verifier<'a> {
+    heap: &'a Heap,
+    function: &'a ObjFunction,
+    // the decoded instruction starting at each offset, None inside operands
+    instructions: Vec<Option<(Instruction, usize)>>,
+    // the functions this one makes closures of
+    nested: Vec<ObjRef>,
+}
+
+// returns the nested functions, which still have to be verified
+fn verify_function(heap: &Heap, function: ObjRef) -> Result<Vec<ObjRef>, VerifyError> {
+    let function = heap.as_function(function);
+    let mut verifier = Verifier {
+        heap,
+        function,
+        instructions: vec![None; function.chunk.code.len()],
+        nested: Vec::new(),
+    };
+    verifier.decode_all()?;
+    verifier.check_stack()?;
+    Ok(verifier.nested)
+}
+
+// how many values an instruction pops, and how many it pushes after that
+fn stack_effect(instruction: Instruction) -> (usize, usize) {
+    match instruction {
+        Instruction::OpConstant(_)
+        | Instruction::OpConstantLong(_)
+        | Instruction::OpNil
+        | Instruction::OpTrue
+        | Instruction::OpFalse
+        | Instruction::OpGetGlobal(_)
+        | Instruction::OpGetLocal(_)
+        | Instruction::OpGetUpvalue(_)
+        | Instruction::OpClosure(_)
+        | Instruction::OpClass(_) => (0, 1),
+        Instruction::OpEqual
+        | Instruction::OpGreater
+        | Instruction::OpLess
+        | Instruction::OpAdd
+        | Instruction::OpSubtract
+        | Instruction::OpMultiply
+        | Instruction::OpDivide
+        | Instruction::OpSetProperty(_)
+        | Instruction::OpGetSuper(_) => (2, 1),
+        Instruction::OpNegate
+        | Instruction::OpNot
+        | Instruction::OpSetGlobal(_)
+        | Instruction::OpSetLocal(_)
+        | Instruction::OpSetUpvalue(_)
+        | Instruction::OpGetProperty(_)
+        | Instruction::OpJumpIfFalse(_) => (1, 1),
+        Instruction::OpPrint
+        | Instruction::OpPop
+        | Instruction::OpDefineGlobal(_)
+        | Instruction::OpCloseUpvalue
+        | Instruction::OpReturn => (1, 0),
+        Instruction::OpMethod(_) | Instruction::OpInherit => (2, 1),
+        Instruction::OpJump(_) | Instruction::OpLoop(_) => (0, 0),
+        // the callee and its arguments are replaced by the result
+        Instruction::OpCall(args) | Instruction::OpInvoke(_, args) => (args + 1, 1),
+        // the superclass sits on top of the arguments
+        Instruction::OpSuperInvoke(_, args) => (args + 2, 1),
+    }
+}
+
+impl Verifier<'_> {
+    fn error(&self, offset: usize, message: String) -> VerifyError {
+        let function = match self.function.name {
+            Some(name) => format!("<fn {}>", self.heap.as_string(name).chars),
+            None => "<script>".to_owned(),
+        };
+        VerifyError {
+            function,
+            offset,
+            message,
+        }
+    }
+
+    fn constant(&self, offset: usize, constant: usize) -> Result<Value, VerifyError> {
+        match self.function.chunk.constants.values.get(constant) {
+            Some(value) => Ok(*value),
+            None => Err(self.error(offset, format!("constant {} is out of range", constant))),
+        }
+    }
+
+    fn string_constant(&self, offset: usize, constant: usize) -> Result<(), VerifyError> {
+        if self.heap.is_string(self.constant(offset, constant)?) {
+            Ok(())
+        } else {
+            Err(self.error(offset, format!("constant {} is not a string", constant)))
+        }
+    }
+
+    fn upvalue(&self, offset: usize, index: usize) -> Result<(), VerifyError> {
+        if index < self.function.upvalue_count {
+            Ok(())
+        } else {
+            Err(self.error(offset, format!("upvalue {} is out of range", index)))
+        }
+    }
+
+    // walks the code front to back, checking every operand that doesn't depend
+    // on the stack, and every function nested in the constants
+    fn decode_all(&mut self) -> Result<(), VerifyError> {
+        let chunk = &self.function.chunk;
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            let (instruction, mut next) = match chunk.decode(offset) {
+                Some(x) => x,
+                None if OpCode::from_byte(chunk.code[offset]).is_none() => {
+                    let message = format!("unknown opcode {}", chunk.code[offset]);
+                    return Err(self.error(offset, message));
+                }
+                None => {
+                    let message = "operands run past the end of the code".to_owned();
+                    return Err(self.error(offset, message));
+                }
+            };
+            match instruction {
+                Instruction::OpConstant(x) | Instruction::OpConstantLong(x) => {
+                    self.constant(offset, x)?;
+                }
+                Instruction::OpDefineGlobal(x)
+                | Instruction::OpGetGlobal(x)
+                | Instruction::OpSetGlobal(x)
+                | Instruction::OpClass(x)
+                | Instruction::OpGetProperty(x)
+                | Instruction::OpSetProperty(x)
+                | Instruction::OpMethod(x)
+                | Instruction::OpInvoke(x, _)
+                | Instruction::OpGetSuper(x)
+                | Instruction::OpSuperInvoke(x, _) => self.string_constant(offset, x)?,
+                Instruction::OpGetUpvalue(x) | Instruction::OpSetUpvalue(x) => {
+                    self.upvalue(offset, x)?
+                }
+                Instruction::OpClosure(x) => next = self.check_closure(offset, x, next)?,
+                _ => {}
+            }
+            self.instructions[offset] = Some((instruction, next));
+            offset = next;
+        }
+        Ok(())
+    }
+
+    // returns the offset after the capture pairs
+    fn check_closure(
+        &mut self,
+        offset: usize,
+        constant: usize,
+        next: usize,
+    ) -> Result<usize, VerifyError> {
+        let (nested_ref, nested) = match self.constant(offset, constant)? {
+            Value::Obj(x) => match self.heap.get(x) {
+                Obj::Function(nested) => (x, nested),
+                _ => {
+                    let message = format!("constant {} is not a function", constant);
+                    return Err(self.error(offset, message));
+                }
+            },
+            _ => {
+                let message = format!("constant {} is not a function", constant);
+                return Err(self.error(offset, message));
+            }
+        };
+        let code = &self.function.chunk.code;
+        let end = next + 2 * nested.upvalue_count;
+        if end > code.len() {
+            let message = "upvalue captures run past the end of the code".to_owned();
+            return Err(self.error(offset, message));
+        }
+        for capture in (next..end).step_by(2) {
+            match code[capture] {
+                // locals depend on the stack, they are checked in check_stack
+                1 => {}
+                0 => self.upvalue(offset, code[capture + 1] as usize)?,
+                _ => {
+                    let message = format!("bad capture flag {}", code[capture]);
+                    return Err(self.error(offset, message));
+                }
+            }
+        }
+        self.nested.push(nested_ref);
+        Ok(end)
+    }
+
+    // follows every path through the code, tracking how deep the stack is.
+    // every instruction has to be reached with the same depth on every path
+    fn check_stack(&self) -> Result<(), VerifyError> {
+        let code = &self.function.chunk.code;
+        let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+        // slot zero holds the callee, then come the arguments
+        let mut worklist = vec![(0, self.function.arity + 1)];
+        if code.is_empty() {
+            return Err(self.error(0, "the function has no code".to_owned()));
+        }
+
+        while let Some((offset, depth)) = worklist.pop() {
+            match depths[offset] {
+                Some(x) if x == depth => continue,
+                Some(x) => {
+                    let message =
+                        format!("stack depth is {} on one path and {} on another", x, depth);
+                    return Err(self.error(offset, message));
+                }
+                None => depths[offset] = Some(depth),
+            }
+            let (instruction, next) = self.instructions[offset].unwrap();
+
+            let (pops, pushes) = stack_effect(instruction);
+            if depth < pops {
+                let message = format!(
+                    "needs {} values on the stack but there are only {}",
+                    pops, depth
+                );
+                return Err(self.error(offset, message));
+            }
+            match instruction {
+                Instruction::OpGetLocal(slot) | Instruction::OpSetLocal(slot) if slot >= depth => {
+                    let message = format!("local slot {} is out of range", slot);
+                    return Err(self.error(offset, message));
+                }
+                Instruction::OpClosure(_) => {
+                    // the captures start after the operand, which is wider behind an OpWide
+                    let (_, captures) = self.function.chunk.decode(offset).unwrap();
+                    for capture in (captures..next).step_by(2) {
+                        let slot = code[capture + 1] as usize;
+                        if code[capture] == 1 && slot >= depth {
+                            let message = format!("captured local slot {} is out of range", slot);
+                            return Err(self.error(offset, message));
+                        }
+                    }
+                }
+                _ => {}
+            }
+            let depth = depth - pops + pushes;
+
+            let targets = match instruction {
+                Instruction::OpReturn => vec![],
+                Instruction::OpJump(jump) => vec![Some(next + jump)],
+                Instruction::OpJumpIfFalse(jump) => vec![Some(next), Some(next + jump)],
+                Instruction::OpLoop(jump) => vec![next.checked_sub(jump)],
+                _ => vec![Some(next)],
+            };
+            for target in targets {
+                match target {
+                    Some(x) if x < code.len() && self.instructions[x].is_some() => {
+                        worklist.push((x, depth))
+                    }
+                    Some(x) if x == next && next == code.len() => {
+                        let message = "execution runs past the end of the code".to_owned();
+                        return Err(self.error(offset, message));
+                    }
+                    Some(x) if x < code.len() => {
+                        let message =
+                            format!("jump target {} is not the start of an instruction", x);
+                        return Err(self.error(offset, message));
+                    }
+                    _ => {
+                        let message = "jump target is outside the code".to_owned();
+                        return Err(self.error(offset, message));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
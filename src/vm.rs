@@ -1,114 +1,1076 @@
 // The Virtual Machine!
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+
 use crate::chunk;
-use crate::chunk::value::Value;
+use crate::chunk::Span;
 use crate::compiler;
+use crate::debug;
+use crate::diagnostic::Diagnostic;
+use crate::object::{
+    DisplayValue, Heap, NativeFn, NativeFunction, NativeKind, Obj, ObjBoundMethod, ObjClass,
+    ObjClosure, ObjInstance, ObjNative, ObjRef, ObjUpvalue,
+};
+use crate::render::{self, Renderer};
+use crate::serialize::{self, LoadError};
+use crate::table::{Entry, Table};
+use crate::value::{values_equal, Value};
+use crate::verify;
 
-const STACK_MAX: usize = 256;
+// how deep calls may nest before the VM reports a stack overflow
+const FRAMES_MAX: usize = 64;
+const STACK_MAX: usize = FRAMES_MAX * 256;
 
-#[repr(u8)]
-#[derive(PartialEq)]
+#[derive(Debug, PartialEq)]
 pub enum InterpretResult {
-    InterpretOK = 1,
-    InterpretCompileError,
-    InterpretRuntimeError,
+    InterpretOK,
+    InterpretRuntimeError(RuntimeError),
 }
 
-pub struct VM {
-    pub chunk: chunk::Chunk,
+impl InterpretResult {
+    pub fn into_result(self) -> Result<(), LoxError> {
+        match self {
+            InterpretResult::InterpretOK => Ok(()),
+            InterpretResult::InterpretRuntimeError(err) => Err(LoxError::Runtime(err)),
+        }
+    }
+}
+
+// everything that can stop a script, as handed to the host
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoxError {
+    Compile(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+    // a compiled script that couldn't be loaded
+    Load(LoadError),
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoxError::Compile(diagnostics) => {
+                for (index, diagnostic) in diagnostics.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            LoxError::Runtime(err) => write!(f, "{}", err),
+            LoxError::Load(err) => write!(f, "could not load the script: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for LoxError {}
+
+// one call that was active when the error happened
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    // None for the top level script
+    pub function: Option<String>,
+    pub line: i32,
+    pub column: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.function {
+            Some(name) => write!(f, "[line {}:{}] in {}()", self.line, self.column, name),
+            None => write!(f, "[line {}:{}] in script", self.line, self.column),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub message: String,
+    // where the failing instruction came from
+    pub line: i32,
+    pub column: usize,
+    pub length: usize,
+    // innermost call first
+    pub trace: Vec<TraceFrame>,
+}
+
+impl RuntimeError {
+    // an error raised by a native, the VM fills in where it happened
+    pub fn init_runtime_error(message: &str) -> Self {
+        Self {
+            message: message.to_owned(),
+            line: 0,
+            column: 0,
+            length: 0,
+            trace: Vec::<TraceFrame>::new(),
+        }
+    }
+}
+
+// lets natives pass on the failure of a call back into Lox with `?`
+impl From<LoxError> for RuntimeError {
+    fn from(err: LoxError) -> Self {
+        match err {
+            LoxError::Runtime(err) => err,
+            err => RuntimeError::init_runtime_error(&err.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        for frame in self.trace.iter() {
+            write!(f, "\n{}", frame)?;
+        }
+        Ok(())
+    }
+}
+
+// an output sink that can still be read after a clone of it is handed to
+// the VM, for capturing what a script prints
+#[derive(Debug, Clone, Default)]
+pub struct SharedBuffer {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+impl SharedBuffer {
+    pub fn init_buffer() -> Self {
+        Self::default()
+    }
+
+    // everything written so far, invalid utf-8 is replaced
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.bytes.borrow()).into_owned()
+    }
+}
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// one ongoing function call
+#[derive(Debug, Clone, Copy)]
+pub struct CallFrame {
+    pub closure: ObjRef,
+    // the closure's function, kept here to save a lookup on every instruction
+    pub function: ObjRef,
     pub inst_pointer: usize, // Rust might not allow pointers to the middle of the array, so use an index insead
-    pub stack: Vec<Value>,
+    // where the frame's window into the VM stack starts, slot zero holds the callee
+    pub slot_base: usize,
+}
+
+pub struct VM {
+    frames: Vec<CallFrame>,
+    pub(crate) stack: Vec<Value>,
+    pub(crate) heap: Heap,
+    globals: Table,
+    // upvalues still pointing into the stack, sorted by the slot they point at
+    open_upvalues: Vec<ObjRef>,
+    // interned once, looked up on every class call
+    init_string: ObjRef,
+    // where `print` and the debug output go
+    output: Box<dyn Write>,
+    // where report writes errors, and whether it colors them
+    errors: Box<dyn Write>,
+    color: bool,
+    // what the repl reads lines from
+    input: Box<dyn BufRead>,
+    // what the natives that are running right now hold on to
+    native_roots: Vec<Value>,
 }
 
 impl VM {
-    pub fn init_vm(chunk: &chunk::Chunk) -> Self {
+    pub fn init_vm() -> Self {
+        let mut heap = Heap::init_heap();
+        let init_string = heap.copy_string("init");
         Self {
-            chunk: chunk.to_owned(),
-            inst_pointer: 0,
-            stack: Vec::<Value>::new(),
+            frames: Vec::<CallFrame>::with_capacity(FRAMES_MAX),
+            stack: Vec::<Value>::with_capacity(STACK_MAX),
+            heap,
+            globals: Table::init_table(),
+            open_upvalues: Vec::<ObjRef>::new(),
+            init_string,
+            output: Box::new(io::stdout()),
+            errors: Box::new(io::stderr()),
+            color: render::stderr_supports_color(),
+            input: Box::new(io::BufReader::new(io::stdin())),
+            native_roots: Vec::<Value>::new(),
         }
     }
 
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    // errors sent anywhere but the terminal are never colored
+    pub fn set_errors(&mut self, errors: impl Write + 'static) {
+        self.errors = Box::new(errors);
+        self.color = false;
+    }
+
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input = Box::new(input);
+    }
+
+    // traces every allocation and collection, the log_gc feature sends it to stderr
+    pub fn set_gc_log(&mut self, log: impl Write + 'static) {
+        self.heap.set_log(Box::new(log));
+    }
+
     fn pop(&mut self) -> Value {
-        match self.stack.last() {
-            Some(x) => {
-                return self.stack.pop().unwrap();
+        // compiled and verified code never pops more than it pushed
+        self.stack.pop().expect("value stack underflow")
+    }
+
+    fn push(&mut self, value: Value) {
+        self.stack.push(value);
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    // everything the running program can still reach directly
+    pub fn roots(&self) -> Vec<Value> {
+        let mut roots = self.stack.clone();
+        roots.extend(self.frames.iter().map(|x| Value::obj(x.closure)));
+        roots.extend(self.open_upvalues.iter().copied().map(Value::obj));
+        roots.extend(self.native_roots.iter().copied());
+        for entry in self.globals.entries.iter() {
+            if let Some(key) = entry.key {
+                roots.push(Value::obj(key));
+                roots.push(entry.value);
+            }
+        }
+        roots.push(Value::obj(self.init_string));
+        roots
+    }
+
+    fn collect_garbage(&mut self) {
+        for root in self.roots() {
+            self.heap.mark_value(root);
+        }
+        self.heap.collect_garbage();
+    }
+
+    // every value the new object refers to must already be reachable from the roots
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    // interns the string, it stays alive only as long as something in the VM refers to it
+    pub fn copy_string(&mut self, chars: &str) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.copy_string(chars)
+    }
+
+    fn take_string(&mut self, chars: String) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.take_string(chars)
+    }
+
+    //compile the source into the top level function,
+    //and then execute it on the VM like any other call.
+    //globals stay defined for the next call
+    pub fn interpret(&mut self, source: &str) -> Result<(), LoxError> {
+        let roots = self.roots();
+        let function =
+            compiler::compile(source, &mut self.heap, roots).map_err(LoxError::Compile)?;
+        // debug output is best effort, a failed write shouldn't stop the script
+        let _ = debug::debug_script(&mut self.output, &self.heap, function);
+        execute(self, function).into_result()
+    }
+
+    // runs a script saved by compile_to_bytes
+    pub fn interpret_bytes(&mut self, bytes: &[u8]) -> Result<(), LoxError> {
+        let function = serialize::read_script(bytes, &mut self.heap).map_err(LoxError::Load)?;
+        // the file may not have come from our compiler, don't trust it
+        verify::verify_script(&self.heap, function)
+            .map_err(|x| LoxError::Load(LoadError::Unverified(x)))?;
+        execute(self, function).into_result()
+    }
+
+    // writes the error to the error sink. source is the text it came from,
+    // if there is one, so the offending line can be shown
+    pub fn report(&mut self, err: &LoxError, source: Option<&str>) {
+        let text = Renderer::init_renderer(source, self.color).error(err);
+        let _ = writeln!(self.errors, "{}", text);
+    }
+
+    // reads lines from the input and runs each of them, until the input
+    // ends or the line is `exit`
+    pub fn repl(&mut self) -> io::Result<()> {
+        loop {
+            write!(self.output, ">>> ")?;
+            self.output.flush()?;
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line == "exit" {
+                break;
             }
-            None => {
-                eprintln!("No values in the stack, expression required");
-                64f64
+            if let Err(err) = self.interpret(line) {
+                // functions defined on earlier lines came from other source, so
+                // runtime errors are reported without a snippet
+                let source = match err {
+                    LoxError::Compile(_) => Some(line),
+                    _ => None,
+                };
+                self.report(&err, source);
             }
         }
+        writeln!(self.output, "Exited.")
     }
 
-    fn push(&mut self, value: Value) {
-        self.stack.push(value);
+    // the value of a global variable, None if it was never defined.
+    // objects in it are only valid until the next collection that can't reach them
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        let name = self.heap.find_string(name)?;
+        self.globals.get(name, self.heap.as_string(name).hash)
     }
-}
 
-pub fn interpret(source: &str) -> InterpretResult {
-    //pass the chunk to the compiler (remember borrow),
-    //fill it with bytecode, and then execute it on the VM
-    let mut chunk = chunk::Chunk::init_chunk();
-    let mut vm = VM::init_vm(&chunk);
-    let mut scanner = compiler::scanner::Scanner::init_scanner();
-    let mut parser = compiler::Parser::init_parser();
+    // defines the global, or overwrites it if it already exists
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        // keep the value reachable while the name is allocated
+        self.push(value);
+        let name = self.copy_string(name);
+        self.set_global_entry(name, value);
+        self.pop();
+    }
 
-    if !compiler::compile(source, &mut chunk, &mut parser, &mut scanner) {
-        return InterpretResult::InterpretCompileError;
+    // the characters of a string value, None for anything else
+    pub fn as_str(&self, value: Value) -> Option<&str> {
+        match value {
+            Value::Obj(x) if self.heap.is_string(value) => Some(&self.heap.as_string(x).chars),
+            _ => None,
+        }
     }
 
-    vm.chunk = chunk;
-    vm.inst_pointer = 0;
-    let result: InterpretResult = run(&mut vm);
+    // formats the value the way `print` would
+    pub fn display(&self, value: Value) -> DisplayValue<'_> {
+        self.heap.display(value)
+    }
 
-    result
-    // InterpretResult::InterpretOK
-}
+    // calls the global function or class called name, and returns what it returned
+    pub fn call_function(&mut self, name: &str, args: &[Value]) -> Result<Value, LoxError> {
+        let (base, stack) = (self.frames.len(), self.stack.len());
+        let result = match self.get_global(name) {
+            Some(callee) => {
+                self.push(callee);
+                self.stack.extend_from_slice(args);
+                match self.call_value(callee, args.len()) {
+                    Some(err) => err,
+                    // a class without an initializer is done as soon as it is called
+                    None if self.frames.len() == base => InterpretResult::InterpretOK,
+                    None => run(self, base),
+                }
+            }
+            None => self.runtime_error(&format!("Undefined variable '{}'.", name)),
+        };
+        if let Err(err) = result.into_result() {
+            self.unwind(base, stack);
+            return Err(err);
+        }
+        Ok(self.pop())
+    }
 
-fn binary_solver(vm: &mut VM, operator: char) {
-    let a = vm.pop();
-    let b = vm.pop();
-    match operator {
-        '+' => vm.push(a + b),
-        '-' => vm.push(b - a),
-        '*' => vm.push(a * b),
-        '/' => {
-            if a == 0f64 {
-                println!("Error! cannot divide by 0");
-                vm.push(b);
-                vm.push(a);
-            } else {
-                vm.push(b / a);
+    // makes function callable from Lox as a global called name
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        self.define_native_kind(name, arity, NativeKind::Plain(function));
+    }
+
+    // like define_native, for natives that keep state between calls
+    pub fn define_native_object(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl NativeFunction + 'static,
+    ) {
+        let function = NativeKind::Object(Rc::new(RefCell::new(function)));
+        self.define_native_kind(name, arity, function);
+    }
+
+    fn define_native_kind(&mut self, name: &str, arity: usize, function: NativeKind) {
+        let name_string = self.copy_string(name);
+        // root the name while the native itself is allocated
+        self.push(Value::obj(name_string));
+        let native = self.alloc(Obj::Native(ObjNative {
+            name: name_string,
+            arity,
+            function,
+        }));
+        self.pop();
+        self.set_global(name, Value::obj(native));
+    }
+
+    // natives run straight away, without a frame of their own. Errors they
+    // return are reported at the call
+    fn call_native(&mut self, native: ObjRef, arg_count: usize) -> Option<InterpretResult> {
+        let (name, arity, function) = match self.heap.get(native) {
+            Obj::Native(x) => (x.name, x.arity, x.function.clone()),
+            _ => unreachable!("call_native on something else"),
+        };
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Some(self.runtime_error(&message));
+        }
+        // the arguments stay on the stack, so they are rooted during the call
+        let args = self.stack[self.stack.len() - arg_count..].to_vec();
+        let result = match function {
+            NativeKind::Plain(function) => function(self, &args),
+            NativeKind::Object(function) => {
+                let mut function = match function.try_borrow_mut() {
+                    Ok(x) => x,
+                    Err(_) => {
+                        let message = format!(
+                            "Native function '{}' can't be called while it is running.",
+                            self.heap.as_string(name).chars
+                        );
+                        return Some(self.runtime_error(&message));
+                    }
+                };
+                // the collector can't borrow it while it runs, so root its values here
+                let roots = self.native_roots.len();
+                self.native_roots.extend(function.trace());
+                let result = function.call(self, &args);
+                self.native_roots.truncate(roots);
+                result
+            }
+        };
+        match result {
+            Ok(value) => {
+                self.stack.truncate(self.stack.len() - arg_count - 1);
+                self.push(value);
+                None
+            }
+            // already traced, it came from a call back into Lox
+            Err(err) if !err.trace.is_empty() => Some(InterpretResult::InterpretRuntimeError(err)),
+            Err(err) => Some(self.runtime_error(&err.message)),
+        }
+    }
+
+    // drops every call above depth frames and every value above slot stack,
+    // what is left is the state from before a failed run
+    fn unwind(&mut self, frames: usize, stack: usize) {
+        self.close_upvalues(stack);
+        self.frames.truncate(frames);
+        self.stack.truncate(stack);
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn chunk(&self) -> &chunk::Chunk {
+        &self.heap.as_function(self.frame().function).chunk
+    }
+
+    fn read_instruction(&mut self) -> Option<chunk::Instruction> {
+        let (instruction, next) = self.chunk().decode(self.frame().inst_pointer)?;
+        self.frame_mut().inst_pointer = next;
+        Some(instruction)
+    }
+
+    fn read_constant(&self, constant: usize) -> Value {
+        self.chunk().constants.values[constant]
+    }
+
+    // names are always interned strings
+    fn read_string(&self, constant: usize) -> ObjRef {
+        self.read_constant(constant).as_obj().unwrap()
+    }
+
+    // builds the error with a trace of the active calls. Whoever started the
+    // run unwinds the stack afterwards
+    fn runtime_error(&mut self, message: &str) -> InterpretResult {
+        let mut trace = Vec::<TraceFrame>::with_capacity(self.frames.len());
+        // walk the frames from the innermost call outwards
+        for frame in self.frames.iter().rev() {
+            let function = self.heap.as_function(frame.function);
+            // the instruction pointer has already moved past the failing instruction
+            let span = function.chunk.get_span(frame.inst_pointer - 1);
+            trace.push(TraceFrame {
+                function: function
+                    .name
+                    .map(|x| self.heap.as_string(x).chars.to_owned()),
+                line: span.line,
+                column: span.column,
+            });
+        }
+        let span = match self.frames.last() {
+            Some(frame) => self
+                .heap
+                .as_function(frame.function)
+                .chunk
+                .get_span(frame.inst_pointer - 1),
+            None => Span::default(),
+        };
+        InterpretResult::InterpretRuntimeError(RuntimeError {
+            message: message.to_owned(),
+            line: span.line,
+            column: span.column,
+            length: span.length,
+            trace,
+        })
+    }
+
+    fn call(&mut self, closure: ObjRef, arg_count: usize) -> Option<InterpretResult> {
+        let function = self.heap.as_closure(closure).function;
+        let arity = self.heap.as_function(function).arity;
+        if arg_count != arity {
+            let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+            return Some(self.runtime_error(&message));
+        }
+        if self.frames.len() == FRAMES_MAX {
+            return Some(self.runtime_error("Stack overflow."));
+        }
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            inst_pointer: 0,
+            slot_base: self.stack.len() - arg_count - 1,
+        });
+        None
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: usize) -> Option<InterpretResult> {
+        if let Value::Obj(obj) = callee {
+            match self.heap.get(obj) {
+                Obj::Closure(_) => return self.call(obj, arg_count),
+                Obj::Native(_) => return self.call_native(obj, arg_count),
+                Obj::BoundMethod(bound) => {
+                    let bound = *bound;
+                    // the receiver takes the callee's slot, so it becomes `this`
+                    let slot = self.stack.len() - arg_count - 1;
+                    self.stack[slot] = bound.receiver;
+                    return self.call(bound.method, arg_count);
+                }
+                Obj::Class(_) => {
+                    let instance = self.alloc(Obj::Instance(ObjInstance {
+                        class: obj,
+                        fields: Table::init_table(),
+                    }));
+                    let slot = self.stack.len() - arg_count - 1;
+                    self.stack[slot] = Value::obj(instance);
+                    let init_hash = self.heap.as_string(self.init_string).hash;
+                    let initializer = self
+                        .heap
+                        .as_class(obj)
+                        .methods
+                        .get(self.init_string, init_hash);
+                    return match initializer {
+                        Some(Value::Obj(initializer)) => self.call(initializer, arg_count),
+                        _ if arg_count != 0 => {
+                            let message = format!("Expected 0 arguments but got {}.", arg_count);
+                            Some(self.runtime_error(&message))
+                        }
+                        _ => None,
+                    };
+                }
+                _ => {}
             }
         }
-        _ => {
-            println!("This operator is not recognized");
+        Some(self.runtime_error("Can only call functions and classes."))
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: ObjRef,
+        name: ObjRef,
+        arg_count: usize,
+    ) -> Option<InterpretResult> {
+        let hash = self.heap.as_string(name).hash;
+        match self.heap.as_class(class).methods.get(name, hash) {
+            Some(Value::Obj(method)) => self.call(method, arg_count),
+            _ => {
+                let message = format!("Undefined property '{}'.", self.heap.as_string(name).chars);
+                Some(self.runtime_error(&message))
+            }
+        }
+    }
+
+    fn invoke(&mut self, name: ObjRef, arg_count: usize) -> Option<InterpretResult> {
+        let receiver = self.peek(arg_count);
+        if !self.heap.is_instance(receiver) {
+            return Some(self.runtime_error("Only instances have methods."));
+        }
+        let instance = receiver.as_obj().unwrap();
+        let hash = self.heap.as_string(name).hash;
+        // a field holding a function shadows a method of the same name
+        if let Some(value) = self.heap.as_instance(instance).fields.get(name, hash) {
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = value;
+            return self.call_value(value, arg_count);
         }
+        let class = self.heap.as_instance(instance).class;
+        self.invoke_from_class(class, name, arg_count)
+    }
+
+    // replaces the instance on top of the stack with the method bound to it
+    fn bind_method(&mut self, class: ObjRef, name: ObjRef) -> Option<InterpretResult> {
+        let hash = self.heap.as_string(name).hash;
+        let method = match self.heap.as_class(class).methods.get(name, hash) {
+            Some(Value::Obj(method)) => method,
+            _ => {
+                let message = format!("Undefined property '{}'.", self.heap.as_string(name).chars);
+                return Some(self.runtime_error(&message));
+            }
+        };
+        let receiver = self.peek(0);
+        let bound = self.alloc(Obj::BoundMethod(ObjBoundMethod { receiver, method }));
+        self.pop();
+        self.push(Value::obj(bound));
+        None
     }
+
+    // returns true if the global is new. The table lives outside the heap,
+    // but its growth still has to count towards the next collection
+    fn set_global_entry(&mut self, name: ObjRef, value: Value) -> bool {
+        let hash = self.heap.as_string(name).hash;
+        let capacity = self.globals.capacity();
+        let is_new = self.globals.set(name, hash, value);
+        let grown = self.globals.capacity() - capacity;
+        self.heap.grow(grown * std::mem::size_of::<Entry>());
+        is_new
+    }
+
+    // the compiler always puts a closure on top of a class, but loaded bytecode may not
+    fn define_method(&mut self, name: ObjRef) -> Option<InterpretResult> {
+        let method = self.peek(0);
+        if !self.heap.is_class(self.peek(1)) {
+            return Some(self.runtime_error("Only classes have methods."));
+        }
+        if !self.heap.is_closure(method) {
+            return Some(self.runtime_error("A method must be a function."));
+        }
+        let class = self.peek(1).as_obj().unwrap();
+        let hash = self.heap.as_string(name).hash;
+        self.heap
+            .as_class_mut(class)
+            .methods
+            .set(name, hash, method);
+        self.heap.resize(class);
+        self.pop();
+        None
+    }
+
+    // reuse the upvalue if another closure already captured this slot,
+    // so both of them see the same variable
+    fn capture_upvalue(&mut self, location: usize) -> ObjRef {
+        let heap = &self.heap;
+        let position =
+            self.open_upvalues
+                .binary_search_by_key(&location, |x| match heap.as_upvalue(*x) {
+                    ObjUpvalue::Open(slot) => *slot,
+                    ObjUpvalue::Closed(_) => unreachable!("closed upvalue in the open list"),
+                });
+        match position {
+            Ok(index) => self.open_upvalues[index],
+            Err(index) => {
+                let upvalue = self.alloc(Obj::Upvalue(ObjUpvalue::Open(location)));
+                self.open_upvalues.insert(index, upvalue);
+                upvalue
+            }
+        }
+    }
+
+    // move every captured variable at or above last out of the stack
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let location = match self.heap.as_upvalue(upvalue) {
+                ObjUpvalue::Open(slot) => *slot,
+                ObjUpvalue::Closed(_) => unreachable!("closed upvalue in the open list"),
+            };
+            if location < last {
+                break;
+            }
+            *self.heap.as_upvalue_mut(upvalue) = ObjUpvalue::Closed(self.stack[location]);
+            self.open_upvalues.pop();
+        }
+    }
+
+    fn read_upvalue(&self, upvalue: ObjRef) -> Value {
+        match self.heap.as_upvalue(upvalue) {
+            ObjUpvalue::Open(slot) => self.stack[*slot],
+            ObjUpvalue::Closed(value) => *value,
+        }
+    }
+
+    fn write_upvalue(&mut self, upvalue: ObjRef, value: Value) {
+        match self.heap.as_upvalue_mut(upvalue) {
+            ObjUpvalue::Open(slot) => {
+                let slot = *slot;
+                self.stack[slot] = value;
+            }
+            ObjUpvalue::Closed(closed) => *closed = value,
+        }
+    }
+}
+
+pub fn interpret(source: &str) -> Result<(), LoxError> {
+    VM::init_vm().interpret(source)
 }
 
-fn run(vm: &mut VM) -> InterpretResult {
-    for x in 0..vm.chunk.code.len() {
-        let op_code = vm.chunk.code[x];
-        vm.inst_pointer += 1;
-        match op_code {
-            chunk::OpCode::OpReturn => {
-                println!("{}", vm.pop());
-                return InterpretResult::InterpretOK;
+// compiles the source without running it, so it can be saved and run later
+pub fn compile_to_bytes(source: &str) -> Result<Vec<u8>, Vec<Diagnostic>> {
+    let mut vm = VM::init_vm();
+    let roots = vm.roots();
+    let function = compiler::compile(source, &mut vm.heap, roots)?;
+    Ok(serialize::write_script(&vm.heap, function))
+}
+
+// runs a script saved by compile_to_bytes
+pub fn interpret_bytes(bytes: &[u8]) -> Result<(), LoxError> {
+    VM::init_vm().interpret_bytes(bytes)
+}
+
+fn execute(vm: &mut VM, function: ObjRef) -> InterpretResult {
+    // the script function isn't rooted yet, so this must not collect
+    let closure = vm.heap.alloc(Obj::Closure(ObjClosure {
+        function,
+        upvalues: Vec::<ObjRef>::new(),
+    }));
+    // natives may run a script in the middle of another one
+    let (base, stack) = (vm.frames.len(), vm.stack.len());
+    vm.push(Value::obj(closure));
+    let result: InterpretResult = match vm.call(closure, 0) {
+        Some(err) => err,
+        None => run(vm, base),
+    };
+    match result {
+        // the script's return value
+        InterpretResult::InterpretOK => {
+            vm.pop();
+        }
+        _ => vm.unwind(base, stack),
+    }
+    let _ = debug::debug_table(&mut vm.output, &vm.heap.strings, "strings");
+
+    result
+}
+
+fn concatenate(vm: &mut VM) {
+    let b = vm.pop().as_obj().unwrap();
+    let a = vm.pop().as_obj().unwrap();
+    let mut chars = vm.heap.as_string(a).chars.to_owned();
+    chars.push_str(&vm.heap.as_string(b).chars);
+    let result = vm.take_string(chars);
+    vm.push(Value::obj(result));
+}
+
+fn binary_solver(vm: &mut VM, operator: char) -> Option<InterpretResult> {
+    let (a, b) = match (vm.peek(0), vm.peek(1)) {
+        (Value::Number(a), Value::Number(b)) => (a, b),
+        _ => return Some(vm.runtime_error("Operands must be numbers.")),
+    };
+    vm.pop();
+    vm.pop();
+    match operator {
+        '+' => vm.push(Value::number(a + b)),
+        '-' => vm.push(Value::number(b - a)),
+        '*' => vm.push(Value::number(a * b)),
+        '>' => vm.push(Value::bool(b > a)),
+        '<' => vm.push(Value::bool(b < a)),
+        '/' => vm.push(Value::number(b / a)),
+        _ => unreachable!("unknown binary operator {}", operator),
+    }
+    None
+}
+
+// runs until the frame at depth base returns, which leaves its result on the stack
+fn run(vm: &mut VM, base: usize) -> InterpretResult {
+    while vm.frame().inst_pointer < vm.chunk().code.len() {
+        let instruction = match vm.read_instruction() {
+            Some(x) => x,
+            None => return vm.runtime_error("Invalid instruction."),
+        };
+        match instruction {
+            chunk::Instruction::OpReturn => {
+                let result = vm.pop();
+                let frame = vm.frames.pop().unwrap();
+                vm.close_upvalues(frame.slot_base);
+                // discard the callee's arguments and locals
+                vm.stack.truncate(frame.slot_base);
+                vm.push(result);
+                if vm.frames.len() == base {
+                    return InterpretResult::InterpretOK;
+                }
+            }
+            chunk::Instruction::OpClosure(constant) => {
+                let function = vm.read_constant(constant).as_obj().unwrap();
+                let upvalue_count = vm.heap.as_function(function).upvalue_count;
+                let mut upvalues = Vec::<ObjRef>::with_capacity(upvalue_count);
+                for _ in 0..upvalue_count {
+                    let capture = vm.chunk().decode_capture(vm.frame().inst_pointer);
+                    vm.frame_mut().inst_pointer += 2;
+                    let upvalue = match capture {
+                        Some((true, index)) => {
+                            let location = vm.frame().slot_base + index;
+                            vm.capture_upvalue(location)
+                        }
+                        Some((false, index)) => {
+                            vm.heap.as_closure(vm.frame().closure).upvalues[index]
+                        }
+                        None => return vm.runtime_error("Expected an upvalue descriptor."),
+                    };
+                    upvalues.push(upvalue);
+                }
+                let closure = vm.alloc(Obj::Closure(ObjClosure { function, upvalues }));
+                vm.push(Value::obj(closure));
+            }
+            chunk::Instruction::OpGetUpvalue(index) => {
+                let upvalue = vm.heap.as_closure(vm.frame().closure).upvalues[index];
+                let value = vm.read_upvalue(upvalue);
+                vm.push(value);
+            }
+            chunk::Instruction::OpSetUpvalue(index) => {
+                let upvalue = vm.heap.as_closure(vm.frame().closure).upvalues[index];
+                let value = vm.peek(0);
+                vm.write_upvalue(upvalue, value);
+            }
+            chunk::Instruction::OpCloseUpvalue => {
+                let top = vm.stack.len() - 1;
+                vm.close_upvalues(top);
+                vm.pop();
+            }
+            chunk::Instruction::OpClass(constant) => {
+                let name = vm.read_string(constant);
+                let class = vm.alloc(Obj::Class(ObjClass {
+                    name,
+                    methods: Table::init_table(),
+                }));
+                vm.push(Value::obj(class));
+            }
+            chunk::Instruction::OpGetProperty(constant) => {
+                let name = vm.read_string(constant);
+                if !vm.heap.is_instance(vm.peek(0)) {
+                    return vm.runtime_error("Only instances have properties.");
+                }
+                let instance = vm.peek(0).as_obj().unwrap();
+                let hash = vm.heap.as_string(name).hash;
+                // fields shadow methods
+                if let Some(value) = vm.heap.as_instance(instance).fields.get(name, hash) {
+                    vm.pop();
+                    vm.push(value);
+                } else {
+                    let class = vm.heap.as_instance(instance).class;
+                    if let Some(err) = vm.bind_method(class, name) {
+                        return err;
+                    }
+                }
+            }
+            chunk::Instruction::OpSetProperty(constant) => {
+                let name = vm.read_string(constant);
+                if !vm.heap.is_instance(vm.peek(1)) {
+                    return vm.runtime_error("Only instances have fields.");
+                }
+                let instance = vm.peek(1).as_obj().unwrap();
+                let hash = vm.heap.as_string(name).hash;
+                let value = vm.peek(0);
+                vm.heap
+                    .as_instance_mut(instance)
+                    .fields
+                    .set(name, hash, value);
+                vm.heap.resize(instance);
+                // leave the assigned value as the result of the expression
+                let value = vm.pop();
+                vm.pop();
+                vm.push(value);
+            }
+            chunk::Instruction::OpMethod(constant) => {
+                let name = vm.read_string(constant);
+                if let Some(err) = vm.define_method(name) {
+                    return err;
+                }
+            }
+            chunk::Instruction::OpInvoke(constant, arg_count) => {
+                let name = vm.read_string(constant);
+                if let Some(err) = vm.invoke(name, arg_count) {
+                    return err;
+                }
+            }
+            chunk::Instruction::OpInherit => {
+                let superclass = match vm.peek(1) {
+                    Value::Obj(x) if matches!(vm.heap.get(x), Obj::Class(_)) => x,
+                    _ => return vm.runtime_error("Superclass must be a class."),
+                };
+                if !vm.heap.is_class(vm.peek(0)) {
+                    return vm.runtime_error("Only classes can inherit.");
+                }
+                let subclass = vm.peek(0).as_obj().unwrap();
+                if superclass == subclass {
+                    return vm.runtime_error("A class can't inherit from itself.");
+                }
+                // copy-down inheritance, methods defined later in the subclass override these
+                let methods = vm.heap.as_class(superclass).methods.clone();
+                methods.add_all(&mut vm.heap.as_class_mut(subclass).methods);
+                vm.heap.resize(subclass);
+                vm.pop();
+            }
+            chunk::Instruction::OpGetSuper(constant) => {
+                let name = vm.read_string(constant);
+                if !vm.heap.is_class(vm.peek(0)) {
+                    return vm.runtime_error("Superclass must be a class.");
+                }
+                let superclass = vm.pop().as_obj().unwrap();
+                if let Some(err) = vm.bind_method(superclass, name) {
+                    return err;
+                }
+            }
+            chunk::Instruction::OpSuperInvoke(constant, arg_count) => {
+                let name = vm.read_string(constant);
+                if !vm.heap.is_class(vm.peek(0)) {
+                    return vm.runtime_error("Superclass must be a class.");
+                }
+                let superclass = vm.pop().as_obj().unwrap();
+                if let Some(err) = vm.invoke_from_class(superclass, name, arg_count) {
+                    return err;
+                }
+            }
+            chunk::Instruction::OpCall(arg_count) => {
+                let callee = vm.peek(arg_count);
+                if let Some(err) = vm.call_value(callee, arg_count) {
+                    return err;
+                }
+            }
+            chunk::Instruction::OpPrint => {
+                let value = vm.pop();
+                if writeln!(vm.output, "{}", vm.heap.display(value)).is_err() {
+                    return vm.runtime_error("Could not write output.");
+                }
+            }
+            chunk::Instruction::OpPop => {
+                vm.pop();
+            }
+            chunk::Instruction::OpGetLocal(slot) => {
+                let value = vm.stack[vm.frame().slot_base + slot];
+                vm.push(value);
+            }
+            chunk::Instruction::OpSetLocal(slot) => {
+                let slot_base = vm.frame().slot_base;
+                vm.stack[slot_base + slot] = vm.peek(0);
+            }
+            chunk::Instruction::OpJump(offset) => vm.frame_mut().inst_pointer += offset,
+            chunk::Instruction::OpJumpIfFalse(offset) => {
+                if vm.peek(0).is_falsey() {
+                    vm.frame_mut().inst_pointer += offset;
+                }
+            }
+            chunk::Instruction::OpLoop(offset) => vm.frame_mut().inst_pointer -= offset,
+            chunk::Instruction::OpDefineGlobal(constant) => {
+                let name = vm.read_string(constant);
+                let value = vm.peek(0);
+                vm.set_global_entry(name, value);
+                vm.pop();
+            }
+            chunk::Instruction::OpGetGlobal(constant) => {
+                let name = vm.read_string(constant);
+                let hash = vm.heap.as_string(name).hash;
+                match vm.globals.get(name, hash) {
+                    Some(value) => vm.push(value),
+                    None => {
+                        let message =
+                            format!("Undefined variable '{}'.", vm.heap.as_string(name).chars);
+                        return vm.runtime_error(&message);
+                    }
+                }
+            }
+            chunk::Instruction::OpSetGlobal(constant) => {
+                let name = vm.read_string(constant);
+                let hash = vm.heap.as_string(name).hash;
+                let value = vm.peek(0);
+                // assignment never creates a variable, undo the insert if it was new
+                if vm.set_global_entry(name, value) {
+                    vm.globals.delete(name, hash);
+                    let message =
+                        format!("Undefined variable '{}'.", vm.heap.as_string(name).chars);
+                    return vm.runtime_error(&message);
+                }
+            }
+            chunk::Instruction::OpNegate => {
+                if !vm.peek(0).is_number() {
+                    return vm.runtime_error("Operand must be a number.");
+                }
+                let neg = vm.pop().as_number().unwrap();
+                vm.push(Value::number(-neg));
+            }
+            chunk::Instruction::OpAdd => {
+                if vm.heap.is_string(vm.peek(0)) && vm.heap.is_string(vm.peek(1)) {
+                    concatenate(vm);
+                } else if vm.peek(0).is_number() && vm.peek(1).is_number() {
+                    binary_solver(vm, '+');
+                } else {
+                    return vm.runtime_error("Operands must be two numbers or two strings.");
+                }
+            }
+            chunk::Instruction::OpSubtract => {
+                if let Some(err) = binary_solver(vm, '-') {
+                    return err;
+                }
+            }
+            chunk::Instruction::OpMultiply => {
+                if let Some(err) = binary_solver(vm, '*') {
+                    return err;
+                }
+            }
+            chunk::Instruction::OpDivide => {
+                if let Some(err) = binary_solver(vm, '/') {
+                    return err;
+                }
+            }
+            chunk::Instruction::OpGreater => {
+                if let Some(err) = binary_solver(vm, '>') {
+                    return err;
+                }
+            }
+            chunk::Instruction::OpLess => {
+                if let Some(err) = binary_solver(vm, '<') {
+                    return err;
+                }
+            }
+            chunk::Instruction::OpEqual => {
+                let a = vm.pop();
+                let b = vm.pop();
+                // strings are interned, so comparing handles is enough
+                vm.push(Value::bool(values_equal(a, b)));
+            }
+            chunk::Instruction::OpNot => {
+                let value = vm.pop();
+                vm.push(Value::bool(value.is_falsey()));
             }
-            chunk::OpCode::OpNegate => {
-                let neg = vm.pop();
-                vm.push(-neg);
+            chunk::Instruction::OpConstant(constant)
+            | chunk::Instruction::OpConstantLong(constant) => {
+                let value = vm.read_constant(constant);
+                vm.push(value);
             }
-            chunk::OpCode::OpAdd => binary_solver(vm, '+'),
-            chunk::OpCode::OpSubtract => binary_solver(vm, '-'),
-            chunk::OpCode::OpMultiply => binary_solver(vm, '*'),
-            chunk::OpCode::OpDivide => binary_solver(vm, '/'),
-            chunk::OpCode::OpConstant(x) => vm.push(x),
-            // _ => {
-            //     panic!("Unknown Operation Instruction encountered");
-            // }
+            chunk::Instruction::OpNil => vm.push(Value::nil()),
+            chunk::Instruction::OpTrue => vm.push(Value::bool(true)),
+            chunk::Instruction::OpFalse => vm.push(Value::bool(false)),
         }
     }
-    InterpretResult::InterpretCompileError
+    vm.runtime_error("Execution ran past the end of the code.")
 }
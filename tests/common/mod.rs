@@ -0,0 +1,19 @@
+// Helpers shared by the tests that drive the VM through the library.
+use std::fmt::Debug;
+
+use rlox::{LoxError, RuntimeError, SharedBuffer, Vm};
+
+// a VM that prints into output and keeps its error reports to itself
+pub fn init_vm(output: &SharedBuffer) -> Vm {
+    let mut vm = Vm::init_vm();
+    vm.set_output(output.clone());
+    vm.set_errors(SharedBuffer::init_buffer());
+    vm
+}
+
+pub fn runtime_error<T: Debug>(result: Result<T, LoxError>) -> RuntimeError {
+    match result {
+        Err(LoxError::Runtime(err)) => err,
+        other => panic!("expected a runtime error, got {:?}", other),
+    }
+}
@@ -0,0 +1,108 @@
+// The library API a Rust program uses to drive a script: reading and writing
+// globals, calling into Lox functions, redirecting its streams and watching
+// the collector.
+mod common;
+
+use rlox::{SharedBuffer, Value};
+
+use common::{init_vm, runtime_error};
+
+#[test]
+fn get_global() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.interpret("var answer = 6 * 7; var name = \"lox\";")
+        .unwrap();
+    assert_eq!(vm.get_global("answer"), Some(Value::number(42.0)));
+    let name = vm.get_global("name").unwrap();
+    assert_eq!(vm.as_str(name), Some("lox"));
+    assert_eq!(vm.get_global("missing"), None);
+}
+
+#[test]
+fn set_global() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.set_global("limit", Value::number(3.0));
+    vm.interpret("var double = limit * 2;").unwrap();
+    assert_eq!(vm.get_global("double"), Some(Value::number(6.0)));
+    // overwrites what the script defined
+    vm.interpret("var flag = true;").unwrap();
+    vm.set_global("flag", Value::bool(false));
+    vm.interpret("var seen = flag;").unwrap();
+    assert_eq!(vm.get_global("seen"), Some(Value::bool(false)));
+}
+
+#[test]
+fn call_function() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.interpret(
+        r#"
+        fun add(a, b) { return a + b; }
+        fun greet(name) { return "hello " + name; }
+        class Point { init(x) { this.x = x; } }
+        "#,
+    )
+    .unwrap();
+
+    let args = [Value::number(1.0), Value::number(2.0)];
+    assert_eq!(vm.call_function("add", &args), Ok(Value::number(3.0)));
+
+    let name = Value::obj(vm.copy_string("lox"));
+    let greeting = vm.call_function("greet", &[name]).unwrap();
+    assert_eq!(vm.as_str(greeting), Some("hello lox"));
+
+    let point = vm.call_function("Point", &[Value::number(1.0)]).unwrap();
+    assert_eq!(vm.display(point).to_string(), "Point instance");
+}
+
+#[test]
+fn call_function_errors() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.interpret("fun add(a, b) { return a + b; }").unwrap();
+
+    let message = |result| runtime_error(result).message;
+    assert_eq!(
+        message(vm.call_function("missing", &[])),
+        "Undefined variable 'missing'."
+    );
+    assert_eq!(
+        message(vm.call_function("add", &[Value::number(1.0)])),
+        "Expected 2 arguments but got 1."
+    );
+    let args = [Value::number(1.0), Value::nil()];
+    assert_eq!(
+        message(vm.call_function("add", &args)),
+        "Operands must be two numbers or two strings."
+    );
+
+    // the failed calls leave nothing behind
+    let args = [Value::number(1.0), Value::number(2.0)];
+    assert_eq!(vm.call_function("add", &args), Ok(Value::number(3.0)));
+}
+
+#[test]
+fn streams() {
+    let output = SharedBuffer::init_buffer();
+    let errors = SharedBuffer::init_buffer();
+    let mut vm = init_vm(&output);
+    vm.set_errors(errors.clone());
+    vm.set_input("var a = 1;\nprint a + 1;\nprint b;\nexit\nprint 3;\n".as_bytes());
+    vm.repl().unwrap();
+    assert_eq!(output.contents(), ">>> >>> 2\n>>> >>> Exited.\n");
+    assert!(
+        errors.contents().contains("Undefined variable 'b'."),
+        "{}",
+        errors.contents()
+    );
+}
+
+#[test]
+fn gc_log() {
+    let log = SharedBuffer::init_buffer();
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.set_gc_log(log.clone());
+    vm.interpret("var s = \"\"; for (var i = 0; i < 2000; i = i + 1) s = s + \"x\";")
+        .unwrap();
+    let log = log.contents();
+    assert!(log.contains("-- gc begin\n"), "{}", log);
+    assert!(log.contains(" free string\n"), "{}", log);
+}
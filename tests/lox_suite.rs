@@ -0,0 +1,178 @@
+// Runs every .lox file under tests/lox and checks it against the annotations
+// in its comments, the same ones the Crafting Interpreters test suite uses:
+//
+//   print 1 + 2;   // expect: 3
+//   print nil.x;   // expect runtime error: Only instances have properties.
+//   print ;        // [line 4] Error at ';': Expect expression.
+//   var a = ;      // Error at ';': Expect expression.
+//
+// rlox reports a column instead of the offending token, so the `at ...` part
+// of a compile error isn't compared. Annotations for jlox only are skipped.
+// A runtime error's column is only compared when the annotation gives one:
+//
+//   a = 1 + 2;     // expect runtime error at column 1: Undefined variable 'a'.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rlox::{LoxError, SharedBuffer, Vm};
+
+#[derive(Debug, Default, PartialEq)]
+struct Outcome {
+    // what the script printed
+    output: Vec<String>,
+    // compile and runtime errors, as "[line N] Error: message"
+    // and "[line N] Runtime error: message"
+    errors: Vec<String>,
+    // where the runtime error was reported
+    column: Option<usize>,
+}
+
+fn lox_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let entries = fs::read_dir(dir).unwrap_or_else(|err| panic!("{}: {}", dir.display(), err));
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            lox_files(&path, files);
+        } else if path.extension().is_some_and(|x| x == "lox") {
+            files.push(path);
+        }
+    }
+}
+
+// drops the ` at 'token'` or ` at end` that clox puts after `Error`
+fn without_location(error: &str) -> String {
+    let rest = error.strip_prefix("Error").unwrap_or(error);
+    let message = if let Some(rest) = rest.strip_prefix(" at end") {
+        rest
+    } else if let Some(rest) = rest.strip_prefix(" at '") {
+        rest.find("':").map_or(rest, |x| &rest[x + 1..])
+    } else {
+        rest
+    };
+    format!("Error{}", message)
+}
+
+fn expected_outcome(source: &str) -> Outcome {
+    let mut expected = Outcome::default();
+    for (index, line) in source.lines().enumerate() {
+        let number = index + 1;
+        let comment = match line.find("//") {
+            Some(x) => line[x + 2..].trim_start(),
+            None => continue,
+        };
+        if let Some(output) = comment.strip_prefix("expect:") {
+            // `// expect: ` with nothing after it is an empty line
+            let output = output.strip_prefix(' ').unwrap_or(output);
+            expected.output.push(output.to_owned());
+        } else if let Some(message) = comment.strip_prefix("expect runtime error: ") {
+            let error = format!("[line {}] Runtime error: {}", number, message);
+            expected.errors.push(error);
+        } else if let Some(rest) = comment.strip_prefix("expect runtime error at column ") {
+            let (column, message) = rest.split_once(": ").expect("bad column annotation");
+            let error = format!("[line {}] Runtime error: {}", number, message);
+            expected.errors.push(error);
+            expected.column = Some(column.parse().expect("bad column annotation"));
+        } else if let Some(rest) = comment
+            .strip_prefix("[line ")
+            .or_else(|| comment.strip_prefix("[c line "))
+        {
+            let (line, error) = rest.split_once("] ").expect("bad [line N] annotation");
+            let error = format!("[line {}] {}", line, without_location(error));
+            expected.errors.push(error);
+        } else if comment.starts_with("Error") {
+            let error = format!("[line {}] {}", number, without_location(comment));
+            expected.errors.push(error);
+        }
+    }
+    expected
+}
+
+fn run(source: &str) -> Outcome {
+    let mut vm = Vm::init_vm();
+    let output = SharedBuffer::init_buffer();
+    vm.set_output(output.clone());
+    vm.set_errors(SharedBuffer::init_buffer());
+
+    let mut column = None;
+    let errors = match vm.interpret(source) {
+        Ok(()) => Vec::new(),
+        Err(LoxError::Compile(diagnostics)) => diagnostics
+            .iter()
+            .map(|x| format!("[line {}] Error: {}", x.span.line, x.message))
+            .collect(),
+        Err(LoxError::Runtime(err)) => {
+            column = Some(err.column);
+            vec![format!(
+                "[line {}] Runtime error: {}",
+                err.line, err.message
+            )]
+        }
+        Err(err) => vec![err.to_string()],
+    };
+    Outcome {
+        output: output.contents().lines().map(str::to_owned).collect(),
+        errors,
+        column,
+    }
+}
+
+// a line by line comparison, enough to see where the two part ways
+fn diff(what: &str, expected: &[String], actual: &[String], report: &mut String) {
+    for index in 0..expected.len().max(actual.len()) {
+        match (expected.get(index), actual.get(index)) {
+            (Some(x), Some(y)) if x == y => {}
+            (Some(x), Some(y)) => report.push_str(&format!(
+                "  {} {}: expected {:?}, got {:?}\n",
+                what,
+                index + 1,
+                x,
+                y
+            )),
+            (Some(x), None) => report.push_str(&format!("  missing {}: {:?}\n", what, x)),
+            (None, Some(y)) => report.push_str(&format!("  unexpected {}: {:?}\n", what, y)),
+            (None, None) => unreachable!(),
+        }
+    }
+}
+
+#[test]
+fn lox_suite() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("lox");
+    let mut files = Vec::<PathBuf>::new();
+    lox_files(&root, &mut files);
+    files.sort();
+    assert!(!files.is_empty(), "no tests found under {}", root.display());
+
+    let mut failures = Vec::<String>::new();
+    for path in files.iter() {
+        let source = fs::read_to_string(path).unwrap();
+        let expected = expected_outcome(&source);
+        let mut actual = run(&source);
+        if expected.column.is_none() {
+            actual.column = None;
+        }
+        if expected != actual {
+            let mut report = format!("{}\n", path.strip_prefix(&root).unwrap().display());
+            diff("output line", &expected.output, &actual.output, &mut report);
+            diff("error", &expected.errors, &actual.errors, &mut report);
+            if expected.column != actual.column {
+                report.push_str(&format!(
+                    "  expected the error at column {:?}, got {:?}\n",
+                    expected.column, actual.column
+                ));
+            }
+            failures.push(report);
+        }
+    }
+
+    if !failures.is_empty() {
+        panic!(
+            "{} of {} Lox tests failed\n\n{}",
+            failures.len(),
+            files.len(),
+            failures.join("\n")
+        );
+    }
+}
@@ -0,0 +1,163 @@
+// Functions written in Rust and called from Lox: plain functions, natives
+// that keep state between calls, and natives that call back into Lox.
+mod common;
+
+use rlox::object::NativeFunction;
+use rlox::{RuntimeError, SharedBuffer, Value, Vm};
+
+use common::{init_vm, runtime_error};
+
+fn add(_: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    match (args[0], args[1]) {
+        (Value::Number(a), Value::Number(b)) => Ok(Value::number(a + b)),
+        _ => Err(RuntimeError::init_runtime_error("add takes two numbers.")),
+    }
+}
+
+// calls the Lox function `step` with n, which is expected to call countdown again
+fn countdown(vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+    if args[0] != Value::number(0.0) {
+        vm.call_function("step", args)?;
+    }
+    Ok(args[0])
+}
+
+#[derive(Default)]
+struct Counter {
+    count: f64,
+}
+
+impl NativeFunction for Counter {
+    fn call(&mut self, _: &mut Vm, _: &[Value]) -> Result<Value, RuntimeError> {
+        self.count += 1.0;
+        Ok(Value::number(self.count))
+    }
+}
+
+// a native that calls back into Lox while it runs
+struct Callback;
+
+impl NativeFunction for Callback {
+    fn call(&mut self, vm: &mut Vm, _: &[Value]) -> Result<Value, RuntimeError> {
+        vm.call_function("callback", &[])?;
+        Ok(Value::nil())
+    }
+}
+
+// remember(value) stores a value, remember(nil) makes garbage and gives the value back
+#[derive(Default)]
+struct Remember {
+    value: Option<Value>,
+}
+
+impl NativeFunction for Remember {
+    fn call(&mut self, vm: &mut Vm, args: &[Value]) -> Result<Value, RuntimeError> {
+        if args[0] != Value::nil() {
+            self.value = Some(args[0]);
+            return Ok(Value::nil());
+        }
+        vm.call_function("churn", &[])?;
+        Ok(self.value.unwrap_or(Value::nil()))
+    }
+
+    fn trace(&self) -> Vec<Value> {
+        self.value.into_iter().collect()
+    }
+}
+
+#[test]
+fn plain_native() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.define_native("add", 2, add);
+    vm.interpret("var sum = add(1, 2);").unwrap();
+    assert_eq!(vm.get_global("sum"), Some(Value::number(3.0)));
+    let add = vm.get_global("add").unwrap();
+    assert_eq!(vm.display(add).to_string(), "<native fn add>");
+}
+
+#[test]
+fn arity() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.define_native("add", 2, add);
+    let err = runtime_error(vm.interpret("\nadd(1);"));
+    assert_eq!(err.message, "Expected 2 arguments but got 1.");
+    assert_eq!(err.line, 2);
+}
+
+#[test]
+fn returned_error() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.define_native("add", 2, add);
+    let err = runtime_error(vm.interpret("fun f() {\n  add(1, nil);\n}\nf();"));
+    assert_eq!(err.message, "add takes two numbers.");
+    assert_eq!(err.line, 2);
+    let trace: Vec<_> = err.trace.iter().map(|x| x.function.as_deref()).collect();
+    assert_eq!(trace, [Some("f"), None]);
+}
+
+#[test]
+fn error_from_a_callback() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.define_native("countdown", 1, countdown);
+    let err = runtime_error(vm.interpret("fun step(n) {\n  return nil + n;\n}\ncountdown(1);"));
+    // the error keeps the trace from inside the callback
+    assert_eq!(err.message, "Operands must be two numbers or two strings.");
+    assert_eq!(err.line, 2);
+    // and the VM can still run afterwards
+    vm.interpret("countdown(0);").unwrap();
+}
+
+#[test]
+fn plain_native_reentered() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.define_native("countdown", 1, countdown);
+    vm.interpret(
+        "var seen = 0; fun step(n) { seen = seen * 10 + n; countdown(n - 1); } countdown(3);",
+    )
+    .unwrap();
+    assert_eq!(vm.get_global("seen"), Some(Value::number(321.0)));
+}
+
+#[test]
+fn native_object() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.define_native_object("counter", 0, Counter::default());
+    vm.interpret("counter(); counter(); var count = counter();")
+        .unwrap();
+    assert_eq!(vm.get_global("count"), Some(Value::number(3.0)));
+}
+
+#[test]
+fn native_object_reentered() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.define_native_object("native", 0, Callback);
+    let err = runtime_error(vm.interpret("fun callback() { native(); }\nnative();"));
+    assert_eq!(
+        err.message,
+        "Native function 'native' can't be called while it is running."
+    );
+    assert_eq!(err.line, 1);
+}
+
+#[test]
+fn traced_values_survive_collection() {
+    let mut vm = init_vm(&SharedBuffer::init_buffer());
+    vm.define_native_object("remember", 1, Remember::default());
+    vm.interpret(
+        r#"
+        # enough garbage to start a collection
+        fun churn() {
+          var s = "";
+          for (var i = 0; i < 2000; i = i + 1) s = s + "x";
+        }
+        # only the native refers to the string
+        remember("remem" + "bered");
+        churn();
+        # collects again while the native is running
+        var remembered = remember(nil);
+        "#,
+    )
+    .unwrap();
+    let remembered = vm.get_global("remembered").unwrap();
+    assert_eq!(vm.as_str(remembered), Some("remembered"));
+}
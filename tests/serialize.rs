@@ -0,0 +1,108 @@
+// Compiled scripts on their way through a file: what a saved script does when
+// it runs again, and how the loader turns down files it can't read.
+use rlox::serialize::{LoadError, FORMAT_VERSION};
+use rlox::vm::compile_to_bytes;
+use rlox::{LoxError, Value, Vm};
+
+// runs the bytes on a fresh VM, which keeps the script's globals
+fn run(bytes: &[u8]) -> Result<Vm, LoxError> {
+    let mut vm = Vm::init_vm();
+    vm.interpret_bytes(bytes)?;
+    Ok(vm)
+}
+
+fn load_error(bytes: &[u8]) -> LoadError {
+    match run(bytes) {
+        Err(LoxError::Load(err)) => err,
+        Ok(_) => panic!("expected a load error"),
+        Err(err) => panic!("expected a load error, got {:?}", err),
+    }
+}
+
+#[test]
+fn round_trip() {
+    let source = r#"
+        fun counter() {
+          var count = 0;
+          fun next() { count = count + 1; return count; }
+          return next;
+        }
+        var next = counter();
+        next();
+        var count = next();
+
+        class Greeter {
+          init(name) { this.name = name; }
+          greet() { return "hello " + this.name; }
+        }
+        var greeting = Greeter("lox").greet();
+        var same = 1.5 == 1.5 and !nil;
+    "#;
+    let bytes = compile_to_bytes(source).unwrap();
+    let vm = run(&bytes).unwrap();
+    assert_eq!(vm.get_global("count"), Some(Value::number(2.0)));
+    let greeting = vm.get_global("greeting").unwrap();
+    assert_eq!(vm.as_str(greeting), Some("hello lox"));
+    assert_eq!(vm.get_global("same"), Some(Value::bool(true)));
+}
+
+#[test]
+fn truncated() {
+    let bytes = compile_to_bytes("print \"truncated\";").unwrap();
+    for length in 6..bytes.len() {
+        assert_eq!(load_error(&bytes[..length]), LoadError::Truncated);
+    }
+}
+
+#[test]
+fn not_bytecode() {
+    assert_eq!(load_error(b"print 1;"), LoadError::NotBytecode);
+}
+
+#[test]
+fn version_mismatch() {
+    let mut bytes = compile_to_bytes("print 1;").unwrap();
+    let found = FORMAT_VERSION + 1;
+    bytes[4..6].copy_from_slice(&found.to_le_bytes());
+    assert_eq!(load_error(&bytes), LoadError::VersionMismatch { found });
+}
+
+#[test]
+fn nested_too_deeply() {
+    let mut bytes = b"LOXC".to_vec();
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    // unnamed functions with no code, each holding the next as its only constant
+    for _ in 0..100_000 {
+        bytes.push(0);
+        bytes.extend_from_slice(&[0; 4 * 4]);
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.push(4);
+    }
+    assert!(matches!(load_error(&bytes), LoadError::Malformed(_)));
+}
+
+#[test]
+fn line_table_stays_small() {
+    let mut source = String::from("var total = 0;\n");
+    for i in 0..1333 {
+        source += &format!("var a{} = {} * 2 + total;\n", i, i);
+        source += &format!(
+            "if (a{} > 10) total = total + a{}; else total = total - 1;\n",
+            i, i
+        );
+        source += "print \"total \" + total;\n";
+    }
+    let bytes = compile_to_bytes(&source).unwrap();
+    // the script's code and then its line table follow the header, the
+    // name flag, the arity and the upvalue count
+    let read_u32 = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+    let code = read_u32(15);
+    let lines = read_u32(19 + code);
+    // an i32 line per byte of code would be code * 4
+    assert!(
+        lines < code * 2,
+        "{} bytes of lines for {} bytes of code",
+        lines,
+        code
+    );
+}
@@ -0,0 +1,250 @@
+// Hand written bytecode the compiler would never produce. The verifier has to
+// turn down what would make the VM index out of bounds, and whatever it lets
+// through with the wrong values on the stack has to end in a runtime error.
+mod common;
+
+use rlox::chunk::{OpCode, Span};
+use rlox::object::{Heap, Obj, ObjFunction, ObjRef};
+use rlox::serialize::{self, LoadError};
+use rlox::{LoxError, SharedBuffer, Value};
+
+use common::{init_vm, runtime_error};
+
+// a constant for the chunk being built
+enum Constant {
+    Name(&'static str),
+    // a function that returns nil, with this many upvalues
+    Function(usize),
+    // a function running the script, named f
+    Nested(Script),
+}
+
+struct Script {
+    code: Vec<u8>,
+    constants: Vec<Constant>,
+    upvalue_count: usize,
+}
+
+impl Script {
+    fn init_script(code: &[u8]) -> Self {
+        Self {
+            code: code.to_vec(),
+            constants: Vec::new(),
+            upvalue_count: 0,
+        }
+    }
+
+    fn constant(mut self, constant: Constant) -> Self {
+        self.constants.push(constant);
+        self
+    }
+
+    fn function(&self, heap: &mut Heap, name: Option<ObjRef>) -> ObjRef {
+        let mut function = ObjFunction::init_function(name);
+        function.upvalue_count = self.upvalue_count;
+        for byte in self.code.iter() {
+            function.chunk.write_chunk(*byte, Span::default());
+        }
+        for constant in self.constants.iter() {
+            let value = match constant {
+                Constant::Name(name) => Value::obj(heap.copy_string(name)),
+                Constant::Function(upvalue_count) => {
+                    let mut nested =
+                        Script::init_script(&[op(OpCode::OpNil), op(OpCode::OpReturn)]);
+                    nested.upvalue_count = *upvalue_count;
+                    let name = heap.copy_string("f");
+                    Value::obj(nested.function(heap, Some(name)))
+                }
+                Constant::Nested(nested) => {
+                    let name = heap.copy_string("f");
+                    Value::obj(nested.function(heap, Some(name)))
+                }
+            };
+            function.chunk.add_constant(value);
+        }
+        heap.alloc(Obj::Function(function))
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut heap = Heap::init_heap();
+        let function = self.function(&mut heap, None);
+        serialize::write_script(&heap, function)
+    }
+
+    fn run(&self) -> Result<(), LoxError> {
+        init_vm(&SharedBuffer::init_buffer()).interpret_bytes(&self.to_bytes())
+    }
+
+    // the verifier's message
+    fn rejected(&self) -> String {
+        match self.run() {
+            Err(LoxError::Load(LoadError::Unverified(err))) => err.message,
+            other => panic!("expected the verifier to reject it, got {:?}", other),
+        }
+    }
+
+    fn runtime_error(&self) -> String {
+        runtime_error(self.run()).message
+    }
+}
+
+fn op(op_code: OpCode) -> u8 {
+    op_code.into()
+}
+
+#[test]
+fn accepts_a_valid_script() {
+    let script = Script::init_script(&[op(OpCode::OpNil), op(OpCode::OpReturn)]);
+    assert!(script.run().is_ok());
+}
+
+#[test]
+fn jump_into_an_operand() {
+    let script = Script::init_script(&[
+        op(OpCode::OpJump),
+        0,
+        1,
+        op(OpCode::OpGetLocal),
+        0,
+        op(OpCode::OpReturn),
+    ]);
+    assert_eq!(
+        script.rejected(),
+        "jump target 4 is not the start of an instruction"
+    );
+}
+
+#[test]
+fn jump_outside_the_code() {
+    let script = Script::init_script(&[op(OpCode::OpJump), 0, 9, op(OpCode::OpReturn)]);
+    assert_eq!(script.rejected(), "jump target is outside the code");
+}
+
+#[test]
+fn stack_underflow() {
+    let script = Script::init_script(&[
+        op(OpCode::OpPop),
+        op(OpCode::OpPop),
+        op(OpCode::OpNil),
+        op(OpCode::OpReturn),
+    ]);
+    assert_eq!(
+        script.rejected(),
+        "needs 1 values on the stack but there are only 0"
+    );
+}
+
+#[test]
+fn local_out_of_range() {
+    let script = Script::init_script(&[op(OpCode::OpGetLocal), 3, op(OpCode::OpReturn)]);
+    assert_eq!(script.rejected(), "local slot 3 is out of range");
+}
+
+#[test]
+fn upvalue_out_of_range() {
+    let script = Script::init_script(&[op(OpCode::OpGetUpvalue), 0, op(OpCode::OpReturn)]);
+    assert_eq!(script.rejected(), "upvalue 0 is out of range");
+}
+
+#[test]
+fn captured_local_out_of_range() {
+    let script = Script::init_script(&[op(OpCode::OpClosure), 0, 1, 5, op(OpCode::OpReturn)])
+        .constant(Constant::Function(1));
+    assert_eq!(script.rejected(), "captured local slot 5 is out of range");
+}
+
+#[test]
+fn script_with_upvalues() {
+    let mut script = Script::init_script(&[op(OpCode::OpGetUpvalue), 0, op(OpCode::OpReturn)]);
+    script.upvalue_count = 1;
+    assert_eq!(script.rejected(), "the script has 1 upvalues");
+}
+
+#[test]
+fn name_is_not_a_string() {
+    let script = Script::init_script(&[op(OpCode::OpGetGlobal), 0, op(OpCode::OpReturn)])
+        .constant(Constant::Function(0));
+    assert_eq!(script.rejected(), "constant 0 is not a string");
+}
+
+#[test]
+fn shared_functions_are_verified_once() {
+    // every level makes four closures of the same function, one level down.
+    // verified once per closure this would be 4^30 functions
+    let mut script = Script::init_script(&[op(OpCode::OpNil), op(OpCode::OpReturn)]);
+    for _ in 0..30 {
+        let mut code = [op(OpCode::OpClosure), 0, op(OpCode::OpPop)].repeat(4);
+        code.extend_from_slice(&[op(OpCode::OpNil), op(OpCode::OpReturn)]);
+        script = Script::init_script(&code).constant(Constant::Nested(script));
+    }
+    assert!(script.run().is_ok());
+}
+
+#[test]
+fn inherit_into_a_non_class() {
+    let script = Script::init_script(&[
+        op(OpCode::OpClass),
+        0,
+        op(OpCode::OpNil),
+        op(OpCode::OpInherit),
+        op(OpCode::OpReturn),
+    ])
+    .constant(Constant::Name("A"));
+    assert_eq!(script.runtime_error(), "Only classes can inherit.");
+}
+
+#[test]
+fn method_on_a_non_class() {
+    let script = Script::init_script(&[
+        op(OpCode::OpNil),
+        op(OpCode::OpClosure),
+        1,
+        op(OpCode::OpMethod),
+        0,
+        op(OpCode::OpReturn),
+    ])
+    .constant(Constant::Name("m"))
+    .constant(Constant::Function(0));
+    assert_eq!(script.runtime_error(), "Only classes have methods.");
+}
+
+#[test]
+fn method_that_is_not_a_function() {
+    let script = Script::init_script(&[
+        op(OpCode::OpClass),
+        0,
+        op(OpCode::OpNil),
+        op(OpCode::OpMethod),
+        0,
+        op(OpCode::OpReturn),
+    ])
+    .constant(Constant::Name("A"));
+    assert_eq!(script.runtime_error(), "A method must be a function.");
+}
+
+#[test]
+fn get_super_from_a_non_class() {
+    let script = Script::init_script(&[
+        op(OpCode::OpNil),
+        op(OpCode::OpNil),
+        op(OpCode::OpGetSuper),
+        0,
+        op(OpCode::OpReturn),
+    ])
+    .constant(Constant::Name("m"));
+    assert_eq!(script.runtime_error(), "Superclass must be a class.");
+}
+
+#[test]
+fn super_invoke_on_a_non_class() {
+    let script = Script::init_script(&[
+        op(OpCode::OpNil),
+        op(OpCode::OpNil),
+        op(OpCode::OpSuperInvoke),
+        0,
+        0,
+        op(OpCode::OpReturn),
+    ])
+    .constant(Constant::Name("m"));
+    assert_eq!(script.runtime_error(), "Superclass must be a class.");
+}